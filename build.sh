@@ -64,8 +64,8 @@ cd "$(dirname "$0")"
 export PATH="/usr/local/bin:/usr/bin:/bin:$PATH"
 export HOME="${HOME:-$(eval echo ~$(whoami))}"
 
-# Launch the actual binary
-exec ./RustyTapes "$@"
+# Launch the actual binary, writing daily-rotated logs to ~/Library/Logs
+exec ./RustyTapes --log-file "$HOME/Library/Logs/RustyTapes.log" "$@"
 EOF
 
 chmod +x "${MACOS_DIR}/launch_wrapper.sh"
@@ -152,7 +152,8 @@ echo " - Double-click ${APP_BUNDLE} to run (will appear in dock)"
 echo " - Drag ${APP_BUNDLE} to Applications folder to install"
 echo " - Cmd+Q to quit the app"
 echo " - App will show in dock with your custom icon"
-echo " - Logs are available at: ~/Library/Logs/RustyTapes.log"
+echo " - Logs are available at: ~/Library/Logs/RustyTapes.<date>.log"
+echo " - Set RUST_LOG (e.g. info,rusty_tapes::ws_reader=warn) to adjust log levels"
 echo ""
 echo "For auto-start at login:"
 echo " - System Preferences → Users & Groups → Login Items"
@@ -0,0 +1,53 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{extract::{ConnectInfo, Request, State}, http::{header, StatusCode}, middleware::Next, response::{IntoResponse, Response}};
+use tracing::warn;
+
+use crate::models::AppState;
+
+/// Requires the configured API token on `/api/*` requests from non-loopback
+/// clients, so local overlays keep working without one. The token may be
+/// sent as a bearer token or as a `token` query parameter for browsers.
+pub async fn require_api_token(State(state): State<Arc<AppState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Response {
+    let Some(expected) = &state.api_token else {
+        return next.run(request).await;
+    };
+    if peer.ip().is_loopback() || !request.uri().path().starts_with("/api/") {
+        return next.run(request).await;
+    }
+
+    let bearer = request.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let query = request.uri().query()
+        .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("token=")))
+        .and_then(|token| urlencoding::decode(token).ok())
+        .map(|token| token.into_owned());
+
+    if bearer.or(query).is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+        next.run(request).await
+    } else {
+        warn!("Rejected unauthenticated request from {} to {}", peer, request.uri().path());
+        (StatusCode::UNAUTHORIZED, "Missing or invalid API token").into_response()
+    }
+}
+
+/// Compares secrets without returning early at the first differing byte, so
+/// response times don't reveal how much of a guessed token was right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_whole_tokens() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(!constant_time_eq(b"s3cret", b"s3crex"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret-longer"));
+        assert!(!constant_time_eq(b"", b"s3cret"));
+    }
+}
@@ -0,0 +1,473 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use serde::Deserialize;
+
+use crate::models::{TrackEventKind, TrackInfo};
+
+/// Settings loaded from the file passed with `--config`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// File that webhook deliveries are appended to once all retries fail
+    pub webhook_dead_letter_file: Option<PathBuf>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub mqtt: Option<MqttConfig>,
+    pub spotify: Option<SpotifyConfig>,
+    pub mpd: Option<MpdConfig>,
+    pub relay: Option<RelayConfig>,
+    pub ingest: IngestConfig,
+    pub polling: PollingConfig,
+    pub history: HistoryConfig,
+    pub sessions: SessionConfig,
+    pub discord: DiscordConfig,
+    /// Rules that hide or redact tracks before they reach the output sinks
+    pub privacy: Vec<PrivacyRuleConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Prefix for the state, event, availability and command topics
+    #[serde(default = "default_mqtt_base_topic")]
+    pub base_topic: String,
+    /// Announce entities via Home Assistant MQTT discovery
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Accept playback commands on `<base_topic>/command`
+    #[serde(default)]
+    pub commands: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub name: Option<String>,
+    /// Event kinds to deliver; all kinds are delivered when empty
+    #[serde(default)]
+    pub events: Vec<TrackEventKind>,
+    /// Shared secret used to sign the body with HMAC-SHA256
+    pub secret: Option<String>,
+    /// Body template rendered with the track placeholders, `{previous_play}` and
+    /// `{previous_play_position}` instead of the default JSON payload
+    pub body_template: Option<String>,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Maximum deliveries per minute; later events wait for a free slot
+    pub max_per_minute: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpotifyConfig {
+    /// Client id of the Spotify app used for the PKCE login
+    pub client_id: String,
+    /// Must match a redirect URI registered for the Spotify app
+    #[serde(default = "default_spotify_redirect_uri")]
+    pub redirect_uri: String,
+    /// Where the access and refresh tokens are stored between runs
+    #[serde(default = "default_spotify_token_file")]
+    pub token_file: PathBuf,
+    #[serde(default = "default_spotify_accounts_url")]
+    pub accounts_url: String,
+    #[serde(default = "default_spotify_api_url")]
+    pub api_url: String,
+    /// Delay between polls while playing, instead of `[polling]`'s
+    #[serde(default = "default_spotify_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MpdConfig {
+    #[serde(default = "default_mpd_host")]
+    pub host: String,
+    #[serde(default = "default_mpd_port")]
+    pub port: u16,
+    pub password: Option<String>,
+}
+
+impl Default for MpdConfig {
+    fn default() -> Self {
+        MpdConfig {
+            host: default_mpd_host(),
+            port: default_mpd_port(),
+            password: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Base URL of the remote Rusty-Tapes server, e.g. `http://studio-mac:7271`
+    pub url: String,
+    /// The remote's `--api-token`, required unless it runs on this machine
+    pub token: Option<String>,
+    /// Follow one of the remote's sources instead of its selected one
+    pub source: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IngestConfig {
+    /// Treat an ingest source as stopped after this long without an update
+    #[serde(default = "default_ingest_stale_after_secs")]
+    pub stale_after_secs: u64,
+    /// Bearer tokens required to push to individual ingest sources
+    #[serde(default)]
+    pub tokens: HashMap<String, String>,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            stale_after_secs: default_ingest_stale_after_secs(),
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+/// Bounds for sources that have to poll their player.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PollingConfig {
+    /// Shortest delay between polls, used around the expected end of a track
+    #[serde(default = "default_polling_min_interval_ms")]
+    pub min_interval_ms: u64,
+    /// Delay between polls while playing
+    #[serde(default = "default_polling_playing_interval_ms")]
+    pub playing_interval_ms: u64,
+    /// Longest delay between polls, used while paused, stopped or not running
+    #[serde(default = "default_polling_max_interval_ms")]
+    pub max_interval_ms: u64,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            min_interval_ms: default_polling_min_interval_ms(),
+            playing_interval_ms: default_polling_playing_interval_ms(),
+            max_interval_ms: default_polling_max_interval_ms(),
+        }
+    }
+}
+
+/// The play log behind `/dashboard` and the stats endpoints.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// JSON Lines file that finished plays are appended to
+    #[serde(default = "default_history_file")]
+    pub file: PathBuf,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: true,
+            file: default_history_file(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Directory that finished stream sessions are saved to
+    #[serde(default = "default_sessions_dir")]
+    pub dir: PathBuf,
+    /// Tracks played for less than this are left out of tracklists by default
+    #[serde(default = "default_min_track_secs")]
+    pub min_track_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            dir: default_sessions_dir(),
+            min_track_secs: default_min_track_secs(),
+        }
+    }
+}
+
+/// Rich presence settings; unset templates keep the built-in wording.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    /// Discord application to publish as, needed for custom asset keys
+    pub client_id: Option<String>,
+    pub details: Option<String>,
+    pub state: Option<String>,
+    pub large_text: Option<String>,
+    pub small_text: Option<String>,
+    /// Asset key for the large image instead of the track's cover artwork
+    pub large_image: Option<String>,
+    /// Asset key used when no cover artwork is found
+    #[serde(default = "default_discord_fallback_image")]
+    pub fallback_image: String,
+    /// Asset keys for the small image; an empty key hides it
+    #[serde(default = "default_discord_favourite_image")]
+    pub favourite_image: String,
+    #[serde(default = "default_discord_unfavourite_image")]
+    pub unfavourite_image: String,
+    /// At most two buttons, whose URLs are templates with encoded values
+    #[serde(default)]
+    pub buttons: Vec<DiscordButton>,
+    /// Genres (case-insensitive) for which presence is cleared
+    #[serde(default)]
+    pub hide_genres: Vec<String>,
+    /// Clear presence while a radio station or mix without track info plays
+    #[serde(default)]
+    pub hide_radio: bool,
+    /// Clear presence once playback has been paused this long; presence is
+    /// always cleared when the player stops or quits
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Default for DiscordConfig {
+    fn default() -> Self {
+        DiscordConfig {
+            client_id: None,
+            details: None,
+            state: None,
+            large_text: None,
+            small_text: None,
+            large_image: None,
+            fallback_image: default_discord_fallback_image(),
+            favourite_image: default_discord_favourite_image(),
+            unfavourite_image: default_discord_unfavourite_image(),
+            buttons: Vec::new(),
+            hide_genres: Vec::new(),
+            hide_radio: false,
+            idle_timeout_secs: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordButton {
+    pub label: String,
+    pub url: String,
+}
+
+impl DiscordConfig {
+    pub fn hides(&self, track: &TrackInfo) -> bool {
+        (self.hide_radio && track.track_name == crate::sources::RADIO_PLACEHOLDER)
+            || self.hide_genres.iter().any(|genre| genre.eq_ignore_ascii_case(&track.genre))
+    }
+}
+
+/// A privacy rule; every pattern that is set must match (case-insensitive
+/// regular expressions) for the rule to apply.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivacyRuleConfig {
+    pub name: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub action: PrivacyAction,
+    /// Fields cleared by the `redact` action
+    #[serde(default)]
+    pub redact: Vec<PrivacyField>,
+    /// Track name shown by the `placeholder` action
+    #[serde(default = "default_privacy_placeholder")]
+    pub placeholder: String,
+    /// Sinks the rule applies to; all of them if empty
+    #[serde(default)]
+    pub sinks: Vec<PrivacySink>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyAction {
+    /// Don't show the track at all
+    Hide,
+    /// Clear the fields listed in `redact`
+    Redact,
+    /// Replace the track with a generic placeholder
+    Placeholder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacyField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+    Artwork,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrivacySink {
+    /// WebSocket, SSE and REST clients such as the overlays
+    Api,
+    Discord,
+    Webhooks,
+    Mqtt,
+    TextOutput,
+    /// The play log and the stats built from it
+    History,
+    /// Stream session tracklists
+    Sessions,
+}
+
+impl WebhookConfig {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.url)
+    }
+
+    pub fn wants(&self, kind: TrackEventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path.display(), e))
+    }
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "rusty-tapes".to_string()
+}
+
+fn default_mqtt_base_topic() -> String {
+    "rusty_tapes".to_string()
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_spotify_redirect_uri() -> String {
+    "http://127.0.0.1:7271/api/spotify/callback".to_string()
+}
+
+fn default_spotify_token_file() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".rusty-tapes").join("spotify-token.json"),
+        None => PathBuf::from("spotify-token.json"),
+    }
+}
+
+fn default_history_file() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".rusty-tapes").join("plays.jsonl"),
+        None => PathBuf::from("plays.jsonl"),
+    }
+}
+
+fn default_sessions_dir() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".rusty-tapes").join("sessions"),
+        None => PathBuf::from("sessions"),
+    }
+}
+
+fn default_min_track_secs() -> u64 {
+    30
+}
+
+fn default_spotify_accounts_url() -> String {
+    "https://accounts.spotify.com".to_string()
+}
+
+fn default_spotify_api_url() -> String {
+    "https://api.spotify.com".to_string()
+}
+
+fn default_spotify_poll_interval_secs() -> u64 {
+    3
+}
+
+fn default_mpd_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_mpd_port() -> u16 {
+    6600
+}
+
+fn default_ingest_stale_after_secs() -> u64 {
+    30
+}
+
+fn default_polling_min_interval_ms() -> u64 {
+    250
+}
+
+fn default_polling_playing_interval_ms() -> u64 {
+    1000
+}
+
+fn default_polling_max_interval_ms() -> u64 {
+    5000
+}
+
+fn default_discord_fallback_image() -> String {
+    "image_logo".to_string()
+}
+
+fn default_discord_favourite_image() -> String {
+    "favourite".to_string()
+}
+
+fn default_discord_unfavourite_image() -> String {
+    "unfavourite".to_string()
+}
+
+fn default_privacy_placeholder() -> String {
+    "Private listening".to_string()
+}
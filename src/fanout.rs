@@ -0,0 +1,123 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use axum::extract::ws::Utf8Bytes;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+/// Frames a client may have queued before it counts as too slow.
+const CLIENT_QUEUE_CAPACITY: usize = 32;
+
+/// A track event serialised once for all API clients. `Utf8Bytes` is
+/// reference counted, so every client sends the same buffer.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// The track event id; 0 for per-source frames, which can't be resumed
+    pub id: u64,
+    pub json: Utf8Bytes,
+}
+
+struct Client {
+    /// The source this client follows, or `None` for the selected playback
+    source: Option<String>,
+    sender: mpsc::Sender<Frame>,
+}
+
+/// Fans frames out to WebSocket and SSE clients through one bounded queue per
+/// client. A client whose queue is full is evicted rather than holding up or
+/// silently skipping events for everyone else; its stream then ends and it
+/// can reconnect (SSE clients resume with `Last-Event-ID`).
+pub struct ClientHub {
+    next_client_id: AtomicU64,
+    clients: Mutex<HashMap<u64, Client>>,
+}
+
+impl ClientHub {
+    pub fn new() -> Self {
+        ClientHub { next_client_id: AtomicU64::new(0), clients: Mutex::new(HashMap::new()) }
+    }
+
+    /// Subscribes a client to the selected playback, or to a single source's
+    /// frames if `source` is set.
+    pub fn subscribe(&self, source: Option<&str>) -> mpsc::Receiver<Frame> {
+        let (sender, receiver) = mpsc::channel(CLIENT_QUEUE_CAPACITY);
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        self.clients.lock().unwrap().insert(id, Client { source: source.map(str::to_string), sender });
+        receiver
+    }
+
+    /// Queues `frame` for every client following the selected playback, or
+    /// `source` if set, dropping disconnected clients and evicting those that
+    /// have fallen a full queue behind.
+    pub fn publish(&self, source: Option<&str>, frame: &Frame, metrics: &Metrics) {
+        self.clients.lock().unwrap().retain(|id, client| {
+            if client.source.as_deref() != source {
+                return true;
+            }
+            match client.sender.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Evicting client {} after it fell {} events behind", id, CLIENT_QUEUE_CAPACITY);
+                    metrics.slow_client_evictions.fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+impl Default for ClientHub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u64) -> Frame {
+        Frame { id, json: Utf8Bytes::from_static("{}") }
+    }
+
+    #[test]
+    fn routes_frames_by_source() {
+        let hub = ClientHub::new();
+        let metrics = Metrics::new();
+        let mut selected = hub.subscribe(None);
+        let mut deck = hub.subscribe(Some("deck"));
+
+        hub.publish(None, &frame(1), &metrics);
+        hub.publish(Some("deck"), &frame(0), &metrics);
+        hub.publish(Some("other"), &frame(0), &metrics);
+
+        assert_eq!(selected.try_recv().unwrap().id, 1);
+        assert!(selected.try_recv().is_err());
+        assert_eq!(deck.try_recv().unwrap().id, 0);
+        assert!(deck.try_recv().is_err());
+    }
+
+    #[test]
+    fn evicts_clients_that_fall_a_queue_behind() {
+        let hub = ClientHub::new();
+        let metrics = Metrics::new();
+        let mut slow = hub.subscribe(Some("deck"));
+        let _selected = hub.subscribe(None);
+
+        for id in 0..=CLIENT_QUEUE_CAPACITY as u64 {
+            hub.publish(Some("deck"), &frame(id), &metrics);
+        }
+        assert_eq!(metrics.slow_client_evictions.load(Ordering::Relaxed), 1);
+        assert_eq!(hub.clients.lock().unwrap().len(), 1);
+
+        // The evicted client drains what it had queued, then its stream ends.
+        let mut received = 0;
+        while slow.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, CLIENT_QUEUE_CAPACITY);
+        assert!(matches!(slow.try_recv(), Err(mpsc::error::TryRecvError::Disconnected)));
+    }
+}
@@ -0,0 +1,573 @@
+use std::{collections::{BTreeMap, HashMap}, io::BufRead, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+
+use axum::{extract::{Query, State}, http::StatusCode, routing::get, Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::{config::HistoryConfig, models::{AppState, TrackInfo}, playback::{self, OpenPlay, PlayEntry, PlayOutcome, Transition}, utils};
+
+const MAX_RECENT_PLAYS: usize = 500;
+/// The longest range the daily listening time is returned for.
+const MAX_DAILY_DAYS: u64 = 366;
+/// How far apart two plays of the same track may start and still be taken
+/// for the same play when importing, as services round timestamps differently.
+const DUPLICATE_WINDOW_MS: u64 = 90_000;
+/// What sources report for a field they don't know.
+pub const UNKNOWN: &str = "Unknown";
+pub const DISABLED: &str = "The play history is disabled";
+
+/// A track that was played, as recorded in the play log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Play {
+    /// When the track started playing, as a Unix time in milliseconds
+    pub started_at: u64,
+    pub track_name: String,
+    pub artist_name: String,
+    pub album: String,
+    pub genre: String,
+    pub source: String,
+    /// The track's length in seconds; 0 if unknown
+    pub duration: f32,
+    /// Time spent playing the track, excluding pauses
+    pub played_secs: f64,
+    /// How the play ended; `None` if that isn't known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ended: Option<PlayOutcome>,
+}
+
+impl Play {
+    fn new(track: &TrackInfo, started_at: SystemTime) -> Self {
+        Play {
+            started_at: utils::unix_millis(started_at),
+            track_name: track.track_name.clone(),
+            artist_name: track.artist_name.clone(),
+            album: track.album.clone(),
+            genre: track.genre.clone(),
+            source: track.source.clone(),
+            duration: track.duration.max(0.0),
+            played_secs: 0.0,
+            ended: None,
+        }
+    }
+
+    fn is_skip(&self) -> bool {
+        matches!(self.ended, Some(PlayOutcome::Skipped { .. }))
+    }
+
+    /// The play as `visible` lets the history see it, applying the privacy
+    /// rules to imported plays like [`PlayHistory::record`] does to live ones.
+    pub fn visible(self, visible: impl Fn(&TrackInfo) -> Option<TrackInfo>) -> Option<Play> {
+        let track = TrackInfo {
+            track_name: self.track_name.clone(),
+            artist_name: self.artist_name.clone(),
+            progress: self.played_secs,
+            duration: self.duration,
+            genre: self.genre.clone(),
+            favourited: false,
+            played_count: 0,
+            album: self.album.clone(),
+            source: self.source.clone(),
+            artwork_url: None,
+            timing: None,
+            state: None,
+        };
+        let track = visible(&track)?;
+        Some(Play { track_name: track.track_name, artist_name: track.artist_name, album: track.album, genre: track.genre, ..self })
+    }
+}
+
+impl PlayEntry for Play {
+    fn add_played_secs(&mut self, secs: f64) {
+        self.played_secs += secs;
+    }
+}
+
+/// Finished plays, appended to a JSON Lines file and kept in memory for the
+/// stats endpoints and `/dashboard`.
+pub struct PlayHistory {
+    plays: Mutex<Vec<Play>>,
+    /// The play being listened to right now
+    current: Mutex<Option<OpenPlay<Play>>>,
+    /// Queues batches of plays for the task appending them to the log file
+    writer: Option<mpsc::UnboundedSender<Vec<Play>>>,
+    /// Serialises imports, so that each is checked for duplicates against
+    /// the plays the previous one added
+    importing: tokio::sync::Mutex<()>,
+    /// Held while this process appends to the log file
+    _lock: Option<std::fs::File>,
+}
+
+impl PlayHistory {
+    /// Loads the plays recorded so far and starts appending new ones, or
+    /// records nothing if the history is disabled.
+    pub fn open(config: &HistoryConfig) -> Result<Self, String> {
+        if !config.enabled {
+            return Ok(PlayHistory {
+                plays: Mutex::new(Vec::new()),
+                current: Mutex::new(None),
+                writer: None,
+                importing: tokio::sync::Mutex::new(()),
+                _lock: None,
+            });
+        }
+
+        let lock = lock_play_log(&config.file)?;
+        let plays = load_plays(&config.file)?;
+        info!("Loaded {} plays from {}", plays.len(), config.file.display());
+
+        let (writer, mut receiver) = mpsc::unbounded_channel::<Vec<Play>>();
+        let path = config.file.clone();
+        tokio::spawn(async move {
+            while let Some(plays) = receiver.recv().await {
+                let result = async {
+                    let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+                    file.write_all(to_lines(&plays).as_bytes()).await
+                }.await;
+                if let Err(e) = result {
+                    warn!("Failed to append to play log {}: {}", path.display(), e);
+                }
+            }
+        });
+
+        Ok(PlayHistory {
+            plays: Mutex::new(plays),
+            current: Mutex::new(None),
+            writer: Some(writer),
+            importing: tokio::sync::Mutex::new(()),
+            _lock: Some(lock),
+        })
+    }
+
+    /// Updates the current play from a playback transition at `at`. `visible`
+    /// applies the privacy rules to a newly started track.
+    pub fn record(&self, transition: &Transition, at: SystemTime, visible: impl Fn(&TrackInfo) -> Option<TrackInfo>) {
+        if self.writer.is_none() {
+            return;
+        }
+        let mut current = self.current.lock().unwrap();
+        let finished = playback::record_transition(&mut current, transition, at, |track| visible(track).map(|track| Play::new(&track, at)));
+        let Some((mut play, ended)) = finished else {
+            return;
+        };
+        play.ended = ended;
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(vec![play.clone()]);
+        }
+        self.plays.lock().unwrap().push(play);
+    }
+
+    /// Adds imported plays that aren't already in the history, returning how
+    /// many were added and how many were duplicates. Duplicates are looked
+    /// for in a copy of the history, so plays keep being recorded meanwhile.
+    pub async fn import(&self, plays: Vec<Play>) -> Result<(usize, usize), String> {
+        let Some(writer) = &self.writer else {
+            return Err(DISABLED.to_string());
+        };
+        let _importing = self.importing.lock().await;
+        let recorded = self.all();
+        let (plays, duplicates) = tokio::task::spawn_blocking(move || without_duplicates(&recorded, plays)).await
+            .map_err(|e| format!("Failed to check the import for duplicates: {}", e))?;
+        let imported = plays.len();
+        if imported > 0 {
+            let _ = writer.send(plays.clone());
+            let mut recorded = self.plays.lock().unwrap();
+            recorded.extend(plays);
+            recorded.sort_by_key(|play| play.started_at);
+        }
+        Ok((imported, duplicates))
+    }
+
+    /// Every recorded play, oldest first.
+    pub fn all(&self) -> Vec<Play> {
+        self.plays.lock().unwrap().clone()
+    }
+
+    /// The most recent plays, newest first.
+    pub fn recent(&self, limit: usize) -> Vec<Play> {
+        self.plays.lock().unwrap().iter().rev().take(limit).cloned().collect()
+    }
+
+    pub fn stats(&self, range: StatsRange, utc_offset_minutes: i32, limit: usize) -> Stats {
+        let since = range.duration().map(|range| utils::unix_millis(SystemTime::now() - range)).unwrap_or(0);
+        let plays = self.plays.lock().unwrap();
+        let plays: Vec<&Play> = plays.iter().filter(|play| play.started_at >= since).collect();
+
+        let daily_since = utils::unix_millis(SystemTime::now() - Duration::from_secs(MAX_DAILY_DAYS * 86_400));
+        let offset = time::UtcOffset::from_whole_seconds(utc_offset_minutes.saturating_mul(60)).unwrap_or(time::UtcOffset::UTC);
+        let mut daily: BTreeMap<String, f64> = BTreeMap::new();
+        for play in plays.iter().filter(|play| play.started_at >= daily_since) {
+            let Ok(started_at) = time::OffsetDateTime::from_unix_timestamp((play.started_at / 1000) as i64) else {
+                continue;
+            };
+            *daily.entry(started_at.to_offset(offset).date().to_string()).or_default() += play.played_secs;
+        }
+
+        let track = |play: &Play| (play.track_name.clone(), Some(play.artist_name.clone()));
+        let artist = |play: &Play| (play.artist_name.clone(), None);
+        Stats {
+            plays: plays.len(),
+            played_secs: plays.iter().map(|play| play.played_secs).sum(),
+            skip_rate: skip_rate(plays.iter().filter(|play| play.ended.is_some()).count(), plays.iter().filter(|play| play.is_skip()).count()),
+            top_tracks: most_played(group(&plays, track), limit),
+            top_artists: most_played(group(&plays, artist), limit),
+            top_albums: most_played(group(&plays, |play| (play.album.clone(), Some(play.artist_name.clone()))), limit),
+            genres: most_played(group(&plays, |play| (play.genre.clone(), None)), usize::MAX),
+            most_skipped_tracks: most_skipped(group(&plays, track), limit),
+            most_skipped_artists: most_skipped(group(&plays, artist), limit),
+            daily: daily.into_iter().map(|(date, played_secs)| DailyListening { date, played_secs }).collect(),
+        }
+    }
+}
+
+/// Splits `incoming` into the plays not already in `recorded` (which must be
+/// sorted by start time) nor earlier in `incoming`, and the number dropped.
+pub fn without_duplicates(recorded: &[Play], mut incoming: Vec<Play>) -> (Vec<Play>, usize) {
+    incoming.sort_by_key(|play| play.started_at);
+    let total = incoming.len();
+    let mut kept: Vec<Play> = Vec::with_capacity(total);
+    for play in incoming {
+        let since = play.started_at.saturating_sub(DUPLICATE_WINDOW_MS);
+        let until = play.started_at + DUPLICATE_WINDOW_MS;
+        let first = recorded.partition_point(|other| other.started_at < since);
+        let duplicate = recorded[first..].iter()
+            .take_while(|other| other.started_at <= until)
+            .chain(kept.iter().rev().take_while(|other| other.started_at >= since))
+            .any(|other| is_same_play(&play, other));
+        if !duplicate {
+            kept.push(play);
+        }
+    }
+    let duplicates = total - kept.len();
+    (kept, duplicates)
+}
+
+/// Whether two plays close in time are of the same track. Exports that don't
+/// name the artist are matched on the track name alone.
+fn is_same_play(a: &Play, b: &Play) -> bool {
+    let known = |artist: &str| !artist.trim().is_empty() && !artist.eq_ignore_ascii_case(UNKNOWN);
+    a.track_name.trim().to_lowercase() == b.track_name.trim().to_lowercase()
+        && (!known(&a.artist_name) || !known(&b.artist_name) || a.artist_name.trim().to_lowercase() == b.artist_name.trim().to_lowercase())
+}
+
+/// Takes the lock held by whichever process appends to the play log at
+/// `path`, creating its directory if needed, so that the `import` command
+/// can't write to it behind a running server's back.
+pub fn lock_play_log(path: &std::path::Path) -> Result<std::fs::File, String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create play log directory {}: {}", parent.display(), e))?;
+    }
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = std::path::PathBuf::from(lock_path);
+    let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)
+        .map_err(|e| format!("Failed to open {}: {}", lock_path.display(), e))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(format!(
+            "The play log {} is in use by a running Rusty-Tapes; import through POST /api/history/import or stop it first", path.display())),
+        Err(std::fs::TryLockError::Error(e)) => Err(format!("Failed to lock {}: {}", lock_path.display(), e)),
+    }
+}
+
+/// Appends plays to the log file in one write, for the `import` command.
+pub fn append_plays(path: &std::path::Path, plays: &[Play]) -> Result<(), String> {
+    use std::io::Write;
+
+    std::fs::OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut file| file.write_all(to_lines(plays).as_bytes()))
+        .map_err(|e| format!("Failed to append to play log {}: {}", path.display(), e))
+}
+
+fn to_lines(plays: &[Play]) -> String {
+    let mut lines = String::new();
+    for play in plays {
+        lines.push_str(&serde_json::to_string(play).unwrap_or_default());
+        lines.push('\n');
+    }
+    lines
+}
+
+pub fn load_plays(path: &std::path::Path) -> Result<Vec<Play>, String> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read play log {}: {}", path.display(), e)),
+    };
+    let mut plays = Vec::new();
+    for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read play log {}: {}", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Play>(&line) {
+            Ok(play) => plays.push(play),
+            Err(e) => warn!("Skipping invalid line {} of play log {}: {}", number + 1, path.display(), e),
+        }
+    }
+    plays.sort_by_key(|play| play.started_at);
+    Ok(plays)
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsRange {
+    Day,
+    Week,
+    #[default]
+    Month,
+    Year,
+    All,
+}
+
+impl StatsRange {
+    fn duration(self) -> Option<Duration> {
+        let days = match self {
+            StatsRange::Day => 1,
+            StatsRange::Week => 7,
+            StatsRange::Month => 30,
+            StatsRange::Year => 365,
+            StatsRange::All => return None,
+        };
+        Some(Duration::from_secs(days * 86_400))
+    }
+}
+
+/// Listening statistics over a range of the play log.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub plays: usize,
+    pub played_secs: f64,
+    /// The share of plays with a known ending that were skipped
+    pub skip_rate: Option<f64>,
+    pub top_tracks: Vec<Ranked>,
+    pub top_artists: Vec<Ranked>,
+    pub top_albums: Vec<Ranked>,
+    pub genres: Vec<Ranked>,
+    pub most_skipped_tracks: Vec<Ranked>,
+    pub most_skipped_artists: Vec<Ranked>,
+    /// Listening time per day, for days with any
+    pub daily: Vec<DailyListening>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Ranked {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    pub plays: usize,
+    pub played_secs: f64,
+    pub skips: usize,
+    /// The share of plays with a known ending that were skipped
+    pub skip_rate: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DailyListening {
+    /// The day in the requested time zone, as `YYYY-MM-DD`
+    pub date: String,
+    pub played_secs: f64,
+}
+
+/// Groups plays by `key`.
+fn group(plays: &[&Play], key: impl Fn(&Play) -> (String, Option<String>)) -> Vec<Ranked> {
+    #[derive(Default)]
+    struct Totals {
+        plays: usize,
+        played_secs: f64,
+        ended: usize,
+        skips: usize,
+    }
+
+    let mut groups: HashMap<(String, Option<String>), Totals> = HashMap::new();
+    for play in plays {
+        let totals = groups.entry(key(play)).or_default();
+        totals.plays += 1;
+        totals.played_secs += play.played_secs;
+        totals.ended += play.ended.is_some() as usize;
+        totals.skips += play.is_skip() as usize;
+    }
+    groups.into_iter()
+        .map(|((name, artist), totals)| Ranked {
+            name,
+            artist,
+            plays: totals.plays,
+            played_secs: totals.played_secs,
+            skips: totals.skips,
+            skip_rate: skip_rate(totals.ended, totals.skips),
+        })
+        .collect()
+}
+
+fn most_played(mut ranked: Vec<Ranked>, limit: usize) -> Vec<Ranked> {
+    ranked.sort_by(|a, b| b.plays.cmp(&a.plays)
+        .then(b.played_secs.total_cmp(&a.played_secs))
+        .then_with(|| a.name.cmp(&b.name)));
+    ranked.truncate(limit);
+    ranked
+}
+
+/// The groups skipped at least once, most skipped first.
+fn most_skipped(mut ranked: Vec<Ranked>, limit: usize) -> Vec<Ranked> {
+    ranked.retain(|ranked| ranked.skips > 0);
+    ranked.sort_by(|a, b| b.skips.cmp(&a.skips)
+        .then(b.skip_rate.unwrap_or(0.0).total_cmp(&a.skip_rate.unwrap_or(0.0)))
+        .then_with(|| a.name.cmp(&b.name)));
+    ranked.truncate(limit);
+    ranked
+}
+
+fn skip_rate(ended: usize, skips: usize) -> Option<f64> {
+    (ended > 0).then(|| skips as f64 / ended as f64)
+}
+
+#[derive(Deserialize)]
+struct RecentParams {
+    #[serde(default = "default_recent_limit")]
+    limit: usize,
+}
+
+fn default_recent_limit() -> usize {
+    50
+}
+
+#[derive(Deserialize)]
+struct StatsParams {
+    #[serde(default)]
+    range: StatsRange,
+    /// The client's offset from UTC in minutes, for grouping plays by day
+    #[serde(default)]
+    utc_offset: i32,
+    #[serde(default = "default_top_limit")]
+    limit: usize,
+}
+
+fn default_top_limit() -> usize {
+    10
+}
+
+/// `GET /api/history` and `GET /api/stats`.
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/history", get(recent_plays))
+        .route("/api/stats", get(listening_stats))
+}
+
+async fn recent_plays(State(state): State<Arc<AppState>>, Query(params): Query<RecentParams>) -> (StatusCode, Json<serde_json::Value>) {
+    let plays = state.history.recent(params.limit.min(MAX_RECENT_PLAYS));
+    (StatusCode::OK, Json(serde_json::json!({ "plays": plays })))
+}
+
+async fn listening_stats(State(state): State<Arc<AppState>>, Query(params): Query<StatsParams>) -> (StatusCode, Json<serde_json::Value>) {
+    let stats = state.history.stats(params.range, params.utc_offset, params.limit);
+    (StatusCode::OK, Json(serde_json::json!(stats)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(track_name: &str, artist_name: &str, played_secs: f64, ended: Option<PlayOutcome>) -> Play {
+        Play {
+            started_at: 0,
+            track_name: track_name.to_string(),
+            artist_name: artist_name.to_string(),
+            album: UNKNOWN.to_string(),
+            genre: UNKNOWN.to_string(),
+            source: "deck".to_string(),
+            duration: 240.0,
+            played_secs,
+            ended,
+        }
+    }
+
+    fn by_track(play: &Play) -> (String, Option<String>) {
+        (play.track_name.clone(), Some(play.artist_name.clone()))
+    }
+
+    #[test]
+    fn groups_plays_and_rates_skips_over_known_endings() {
+        let skipped = Some(PlayOutcome::Skipped { position: 30.0 });
+        let plays = [
+            play("Roads", "Portishead", 240.0, Some(PlayOutcome::Completed)),
+            play("Roads", "Portishead", 30.0, skipped),
+            play("Roads", "Portishead", 100.0, None),
+            play("Roads", "Cover Band", 200.0, None),
+        ];
+        let plays: Vec<&Play> = plays.iter().collect();
+
+        let ranked = most_played(group(&plays, by_track), 10);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!((ranked[0].artist.as_deref(), ranked[0].plays, ranked[0].skips), (Some("Portishead"), 3, 1));
+        assert_eq!(ranked[0].played_secs, 370.0);
+        // The play without a known ending doesn't count towards the rate.
+        assert_eq!(ranked[0].skip_rate, Some(0.5));
+        assert_eq!(ranked[1].skip_rate, None);
+    }
+
+    #[test]
+    fn ranks_by_plays_then_time_then_name() {
+        let plays = [
+            play("Teardrop", "Massive Attack", 100.0, None),
+            play("Angel", "Massive Attack", 100.0, None),
+            play("Glory Box", "Portishead", 300.0, None),
+            play("Sour Times", "Portishead", 10.0, None),
+            play("Sour Times", "Portishead", 10.0, None),
+        ];
+        let plays: Vec<&Play> = plays.iter().collect();
+
+        let names: Vec<String> = most_played(group(&plays, by_track), 3).into_iter().map(|ranked| ranked.name).collect();
+        assert_eq!(names, ["Sour Times", "Glory Box", "Angel"]);
+    }
+
+    #[test]
+    fn most_skipped_leaves_out_groups_never_skipped() {
+        let skipped = || Some(PlayOutcome::Skipped { position: 5.0 });
+        let plays = [
+            play("Teardrop", "Massive Attack", 5.0, skipped()),
+            play("Teardrop", "Massive Attack", 5.0, skipped()),
+            play("Roads", "Portishead", 5.0, skipped()),
+            play("Roads", "Portishead", 240.0, Some(PlayOutcome::Completed)),
+            play("Angel", "Massive Attack", 240.0, Some(PlayOutcome::Completed)),
+        ];
+        let plays: Vec<&Play> = plays.iter().collect();
+
+        let ranked = most_skipped(group(&plays, by_track), 10);
+        let skips: Vec<(&str, usize)> = ranked.iter().map(|ranked| (ranked.name.as_str(), ranked.skips)).collect();
+        assert_eq!(skips, [("Teardrop", 2), ("Roads", 1)]);
+        assert_eq!(ranked[1].skip_rate, Some(0.5));
+    }
+
+    fn imported(track_name: &str, artist_name: &str, started_at: u64) -> Play {
+        Play { started_at, ..play(track_name, artist_name, 0.0, None) }
+    }
+
+    #[test]
+    fn drops_plays_started_within_the_duplicate_window() {
+        let recorded = [imported("Roads", "Portishead", 1_000_000)];
+        let incoming = vec![
+            imported("roads ", "PORTISHEAD", 1_000_000 + DUPLICATE_WINDOW_MS),
+            imported("Roads", "Portishead", 1_000_000 + DUPLICATE_WINDOW_MS + 1),
+            imported("Roads", "Cover Band", 1_000_000 - 1_000),
+            // Exports without the artist match on the track name alone.
+            imported("Roads", UNKNOWN, 1_000_000 - DUPLICATE_WINDOW_MS),
+        ];
+        let (kept, duplicates) = without_duplicates(&recorded, incoming);
+        assert_eq!(duplicates, 2);
+        let kept: Vec<(&str, u64)> = kept.iter().map(|play| (play.artist_name.as_str(), play.started_at)).collect();
+        assert_eq!(kept, [("Cover Band", 999_000), ("Portishead", 1_090_001)]);
+    }
+
+    #[test]
+    fn drops_duplicates_within_the_import() {
+        let incoming = vec![
+            imported("Teardrop", "Massive Attack", 60_000),
+            imported("Teardrop", "Massive Attack", 0),
+            imported("Teardrop", "Massive Attack", 200_000),
+        ];
+        let (kept, duplicates) = without_duplicates(&[], incoming);
+        assert_eq!(duplicates, 1);
+        assert_eq!(kept.iter().map(|play| play.started_at).collect::<Vec<_>>(), [0, 200_000]);
+    }
+}
@@ -0,0 +1,481 @@
+use std::{collections::HashMap, io::Write, path::Path, sync::Arc, time::{Duration, UNIX_EPOCH}};
+
+use axum::{body::{Body, Bytes}, extract::{DefaultBodyLimit, Query, State}, http::{header, StatusCode}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, PrimitiveDateTime};
+use tracing::info;
+
+use crate::{config::{Config, HistoryConfig, PrivacySink}, history::{self, Play, UNKNOWN}, models::AppState, playback::PlayOutcome, privacy::PrivacyFilter, utils};
+
+/// Apple's privacy data download can hold years of play activity.
+const MAX_IMPORT_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// A JSON array of plays
+    Json,
+    /// One JSON play per line, like the play log itself
+    Jsonl,
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Json => "application/json",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// A Last.fm scrobble export: `artist,album,track,date` rows, with or
+    /// without a header
+    Lastfm,
+    /// "Apple Music Play Activity.csv" from Apple's privacy data download
+    AppleMusic,
+    /// A CSV export from Rusty-Tapes
+    Csv,
+    /// A JSON export from Rusty-Tapes
+    Json,
+    /// A JSON Lines export or play log from Rusty-Tapes
+    Jsonl,
+}
+
+/// A play as a CSV row, with a readable start time.
+#[derive(Serialize, Deserialize)]
+struct CsvPlay {
+    /// RFC 3339
+    started_at: String,
+    track_name: String,
+    artist_name: String,
+    album: String,
+    genre: String,
+    source: String,
+    duration: f32,
+    played_secs: f64,
+    /// `completed`, `skipped`, `interrupted`, or empty if unknown
+    #[serde(default)]
+    ended: String,
+    /// Where a skipped or interrupted play ended, in seconds
+    #[serde(default)]
+    ended_at_position: Option<f64>,
+}
+
+impl CsvPlay {
+    fn ended(&self) -> Option<PlayOutcome> {
+        let position = self.ended_at_position.unwrap_or(self.played_secs);
+        match self.ended.as_str() {
+            "completed" => Some(PlayOutcome::Completed),
+            "skipped" => Some(PlayOutcome::Skipped { position }),
+            "interrupted" => Some(PlayOutcome::Interrupted { position }),
+            _ => None,
+        }
+    }
+}
+
+/// The plays read from an import, and the number of rows that weren't plays,
+/// couldn't be read or are hidden from the history by a privacy rule.
+pub struct Parsed {
+    pub plays: Vec<Play>,
+    pub skipped: usize,
+}
+
+pub fn export(plays: &[Play], format: ExportFormat) -> Result<Vec<u8>, String> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for play in plays {
+                let (ended, ended_at_position) = match play.ended {
+                    Some(PlayOutcome::Completed) => ("completed", None),
+                    Some(PlayOutcome::Skipped { position }) => ("skipped", Some(position)),
+                    Some(PlayOutcome::Interrupted { position }) => ("interrupted", Some(position)),
+                    None => ("", None),
+                };
+                writer.serialize(CsvPlay {
+                    started_at: utils::rfc3339(UNIX_EPOCH + Duration::from_millis(play.started_at)),
+                    track_name: play.track_name.clone(),
+                    artist_name: play.artist_name.clone(),
+                    album: play.album.clone(),
+                    genre: play.genre.clone(),
+                    source: play.source.clone(),
+                    duration: play.duration,
+                    played_secs: play.played_secs,
+                    ended: ended.to_string(),
+                    ended_at_position,
+                }).map_err(|e| format!("Failed to write CSV: {}", e))?;
+            }
+            writer.into_inner().map_err(|e| format!("Failed to write CSV: {}", e))
+        }
+        ExportFormat::Json => serde_json::to_vec_pretty(plays).map_err(|e| format!("Failed to write JSON: {}", e)),
+        ExportFormat::Jsonl => {
+            let mut lines = Vec::new();
+            for play in plays {
+                serde_json::to_writer(&mut lines, play).map_err(|e| format!("Failed to write JSON: {}", e))?;
+                lines.push(b'\n');
+            }
+            Ok(lines)
+        }
+    }
+}
+
+pub fn parse(data: &[u8], format: ImportFormat) -> Result<Parsed, String> {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    match format {
+        ImportFormat::Lastfm => parse_lastfm(data),
+        ImportFormat::AppleMusic => parse_apple_music(data),
+        ImportFormat::Csv => {
+            let mut parsed = Parsed { plays: Vec::new(), skipped: 0 };
+            for row in csv::Reader::from_reader(data).deserialize::<CsvPlay>() {
+                let row = row.map_err(|e| format!("Invalid CSV: {}", e))?;
+                match parse_timestamp(&row.started_at) {
+                    Some(started_at) => parsed.plays.push(Play {
+                        started_at,
+                        ended: row.ended(),
+                        track_name: row.track_name,
+                        artist_name: row.artist_name,
+                        album: row.album,
+                        genre: row.genre,
+                        source: row.source,
+                        duration: row.duration,
+                        played_secs: row.played_secs,
+                    }),
+                    None => parsed.skipped += 1,
+                }
+            }
+            Ok(parsed)
+        }
+        ImportFormat::Json => {
+            let plays = serde_json::from_slice(data).map_err(|e| format!("Invalid JSON: {}", e))?;
+            Ok(Parsed { plays, skipped: 0 })
+        }
+        ImportFormat::Jsonl => {
+            let mut parsed = Parsed { plays: Vec::new(), skipped: 0 };
+            for line in data.split(|byte| *byte == b'\n').filter(|line| !line.trim_ascii().is_empty()) {
+                match serde_json::from_slice(line) {
+                    Ok(play) => parsed.plays.push(play),
+                    Err(_) => parsed.skipped += 1,
+                }
+            }
+            Ok(parsed)
+        }
+    }
+}
+
+/// Parses an import and applies the history's privacy rules to its plays.
+fn parse_visible(data: &[u8], format: ImportFormat, privacy: &PrivacyFilter) -> Result<Parsed, String> {
+    let parsed = parse(data, format)?;
+    let total = parsed.plays.len();
+    let plays: Vec<Play> = parsed.plays.into_iter()
+        .filter_map(|play| play.visible(|track| privacy.filter_track(PrivacySink::History, track)))
+        .collect();
+    Ok(Parsed { skipped: parsed.skipped + total - plays.len(), plays })
+}
+
+/// Last.fm exports name the columns only sometimes; without a header the
+/// columns are `artist,album,track,date`, as written by lastfm-to-csv.
+fn parse_lastfm(data: &[u8]) -> Result<Parsed, String> {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).from_reader(data);
+    let mut rows = reader.records();
+    let first = match rows.next() {
+        Some(row) => row.map_err(|e| format!("Invalid CSV: {}", e))?,
+        None => return Ok(Parsed { plays: Vec::new(), skipped: 0 }),
+    };
+
+    let columns = Columns::new(&first);
+    let has_header = columns.find(&["artist"]).is_some() && columns.find(&["track", "name", "title"]).is_some();
+    let (artist, album, track, date) = match has_header {
+        true => (
+            columns.find(&["artist"]),
+            columns.find(&["album"]),
+            columns.find(&["track", "name", "title"]),
+            columns.find(&["uts", "timestamp", "date", "time"]),
+        ),
+        false => (Some(0), Some(1), Some(2), Some(3)),
+    };
+
+    let mut parsed = Parsed { plays: Vec::new(), skipped: 0 };
+    let rows = (!has_header).then_some(Ok(first)).into_iter().chain(rows);
+    for row in rows {
+        let row = row.map_err(|e| format!("Invalid CSV: {}", e))?;
+        let field = |index: Option<usize>| index.and_then(|index| row.get(index)).map(str::trim).unwrap_or("");
+        let track_name = field(track);
+        let Some(started_at) = parse_lastfm_date(field(date)).filter(|_| !track_name.is_empty()) else {
+            parsed.skipped += 1;
+            continue;
+        };
+        parsed.plays.push(Play {
+            started_at,
+            track_name: track_name.to_string(),
+            artist_name: or_unknown(field(artist)),
+            album: or_unknown(field(album)),
+            genre: UNKNOWN.to_string(),
+            source: "lastfm".to_string(),
+            duration: 0.0,
+            played_secs: 0.0,
+            ended: None,
+        });
+    }
+    Ok(parsed)
+}
+
+/// Apple's export has one row per playback event; a play is a `PLAY_END`
+/// event, which says how long the track was listened to.
+fn parse_apple_music(data: &[u8]) -> Result<Parsed, String> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data);
+    let columns = Columns::new(reader.headers().map_err(|e| format!("Invalid CSV: {}", e))?);
+    let Some(song) = columns.find(&["song name"]) else {
+        return Err("Not an Apple Music Play Activity export: no \"Song Name\" column".to_string());
+    };
+    let artist = columns.find(&["artist name"]);
+    let album = columns.find(&["album name"]);
+    let event_type = columns.find(&["event type"]);
+    let started = columns.find(&["event start timestamp"]);
+    let ended = columns.find(&["event end timestamp"]);
+    let played = columns.find(&["play duration milliseconds"]);
+    let length = columns.find(&["media duration in milliseconds"]);
+    let end_reason = columns.find(&["end reason type"]);
+
+    let mut parsed = Parsed { plays: Vec::new(), skipped: 0 };
+    for row in reader.records() {
+        let row = row.map_err(|e| format!("Invalid CSV: {}", e))?;
+        let field = |index: Option<usize>| index.and_then(|index| row.get(index)).map(str::trim).unwrap_or("");
+        let millis = |index: Option<usize>| field(index).parse::<f64>().ok().filter(|millis| *millis > 0.0);
+
+        let track_name = field(Some(song));
+        if track_name.is_empty() || event_type.is_some_and(|_| field(event_type) != "PLAY_END") {
+            parsed.skipped += 1;
+            continue;
+        }
+        let played_ms = millis(played).unwrap_or(0.0);
+        let started_at = parse_timestamp(field(started))
+            .or_else(|| parse_timestamp(field(ended)).map(|ended| ended.saturating_sub(played_ms as u64)));
+        let Some(started_at) = started_at else {
+            parsed.skipped += 1;
+            continue;
+        };
+        let position = played_ms / 1000.0;
+        let ended = match field(end_reason) {
+            "" => None,
+            "NATURAL_END_OF_TRACK" => Some(PlayOutcome::Completed),
+            "TRACK_SKIPPED_FORWARDS" | "TRACK_SKIPPED_BACKWARDS" => Some(PlayOutcome::Skipped { position }),
+            _ => Some(PlayOutcome::Interrupted { position }),
+        };
+        parsed.plays.push(Play {
+            started_at,
+            track_name: track_name.to_string(),
+            artist_name: or_unknown(field(artist)),
+            album: or_unknown(field(album)),
+            genre: UNKNOWN.to_string(),
+            source: "apple_music".to_string(),
+            duration: (millis(length).unwrap_or(0.0) / 1000.0) as f32,
+            played_secs: position,
+            ended,
+        });
+    }
+    Ok(parsed)
+}
+
+/// Column indices by lowercased header name.
+struct Columns(HashMap<String, usize>);
+
+impl Columns {
+    fn new(header: &csv::StringRecord) -> Self {
+        Columns(header.iter().enumerate().map(|(index, name)| (name.trim().to_lowercase(), index)).collect())
+    }
+
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        names.iter().find_map(|name| self.0.get(*name).copied())
+    }
+}
+
+fn or_unknown(value: &str) -> String {
+    match value {
+        "" => UNKNOWN.to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// An RFC 3339 time, or a Unix time in seconds or milliseconds, as Unix
+/// milliseconds.
+fn parse_timestamp(value: &str) -> Option<u64> {
+    if let Ok(number) = value.parse::<u64>() {
+        return Some(if number < 100_000_000_000 { number * 1000 } else { number });
+    }
+    let time = OffsetDateTime::parse(value, &Rfc3339).ok()?;
+    u64::try_from(time.unix_timestamp_nanos() / 1_000_000).ok()
+}
+
+/// Last.fm shows scrobble times in UTC as e.g. `31 Jan 2024 18:03`.
+fn parse_lastfm_date(value: &str) -> Option<u64> {
+    parse_timestamp(value).or_else(|| {
+        let format = time::format_description::parse_borrowed::<2>("[day padding:none] [month repr:short case_sensitive:false] [year] [hour padding:none]:[minute]").ok()?;
+        let time = PrimitiveDateTime::parse(value, &format).ok()?.assume_utc();
+        u64::try_from(time.unix_timestamp()).ok().map(|seconds| seconds * 1000)
+    })
+}
+
+/// The `export` command: writes the play log to `output`, or to stdout.
+pub fn export_to(format: ExportFormat, output: Option<&Path>, config: &HistoryConfig) -> Result<(), String> {
+    let plays = history::load_plays(&config.file)?;
+    let data = export(&plays, format)?;
+    match output {
+        Some(path) => std::fs::write(path, &data).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?,
+        None => std::io::stdout().write_all(&data).map_err(|e| format!("Failed to write the export: {}", e))?,
+    }
+    info!("Exported {} plays", plays.len());
+    Ok(())
+}
+
+/// The `import` command: appends the plays in `file` to the play log, which
+/// a running server must not be using.
+pub fn import_from(format: ImportFormat, file: &Path, config: &Config) -> Result<(), String> {
+    if !config.history.enabled {
+        return Err(history::DISABLED.to_string());
+    }
+    let _lock = history::lock_play_log(&config.history.file)?;
+    let privacy = PrivacyFilter::new(&config.privacy)?;
+    let data = std::fs::read(file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+    let parsed = parse_visible(&data, format, &privacy)?;
+    let recorded = history::load_plays(&config.history.file)?;
+    let (plays, duplicates) = history::without_duplicates(&recorded, parsed.plays);
+    history::append_plays(&config.history.file, &plays)?;
+    info!("Imported {} plays into {} ({} already recorded, {} rows skipped)",
+        plays.len(), config.history.file.display(), duplicates, parsed.skipped);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Deserialize)]
+struct ImportParams {
+    format: ImportFormat,
+}
+
+/// `GET /api/history/export` and `POST /api/history/import`.
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/history/export", get(export_history))
+        .route("/api/history/import", post(import_history).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)))
+}
+
+async fn export_history(State(state): State<Arc<AppState>>, Query(params): Query<ExportParams>) -> Response {
+    match export(&state.history.all(), params.format) {
+        Ok(data) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, params.format.content_type())
+            .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"plays.{}\"", params.format.extension()))
+            .body(Body::from(data))
+            .unwrap(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(serde_json::json!({ "error": e }))).into_response(),
+    }
+}
+
+async fn import_history(State(state): State<Arc<AppState>>, Query(params): Query<ImportParams>, body: Bytes) -> (StatusCode, Json<serde_json::Value>) {
+    let parsing = state.clone();
+    let parsed = tokio::task::spawn_blocking(move || parse_visible(&body, params.format, &parsing.privacy)).await
+        .unwrap_or_else(|e| Err(format!("Failed to parse the import: {}", e)));
+    let parsed = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e }))),
+    };
+    match state.history.import(parsed.plays).await {
+        Ok((imported, duplicates)) => {
+            info!("Imported {} plays ({} already recorded, {} rows skipped)", imported, duplicates, parsed.skipped);
+            (StatusCode::OK, Json(serde_json::json!({ "imported": imported, "duplicates": duplicates, "skipped": parsed.skipped })))
+        }
+        Err(e) => (StatusCode::CONFLICT, Json(serde_json::json!({ "error": e }))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APPLE_MUSIC_HEADER: &str = "Song Name,Artist Name,Album Name,Event Type,Event Start Timestamp,Event End Timestamp,Play Duration Milliseconds,Media Duration In Milliseconds,End Reason Type\n";
+
+    #[test]
+    fn reads_lastfm_exports_with_a_header() {
+        let data = b"uts,utc_time,artist,artist_mbid,album,album_mbid,track,track_mbid\n\
+            1706724180,31 Jan 2024 18:03,Portishead,,Dummy,,Roads,\n\
+            1706724480,31 Jan 2024 18:08,Portishead,,,,,\n";
+        let parsed = parse(data, ImportFormat::Lastfm).unwrap();
+        assert_eq!(parsed.skipped, 1);
+        assert_eq!(parsed.plays.len(), 1);
+        let play = &parsed.plays[0];
+        assert_eq!((play.track_name.as_str(), play.artist_name.as_str(), play.album.as_str()), ("Roads", "Portishead", "Dummy"));
+        assert_eq!(play.started_at, 1_706_724_180_000);
+        assert_eq!(play.source, "lastfm");
+    }
+
+    #[test]
+    fn reads_lastfm_exports_without_a_header() {
+        let data = b"Massive Attack,,Teardrop,31 Jan 2024 18:03\nMassive Attack,Mezzanine,Angel,\n";
+        let parsed = parse(data, ImportFormat::Lastfm).unwrap();
+        assert_eq!(parsed.skipped, 1);
+        assert_eq!(parsed.plays.len(), 1);
+        let play = &parsed.plays[0];
+        assert_eq!((play.track_name.as_str(), play.artist_name.as_str(), play.album.as_str()), ("Teardrop", "Massive Attack", UNKNOWN));
+        assert_eq!(play.started_at, 1_706_724_180_000);
+    }
+
+    #[test]
+    fn reads_play_end_events_from_apple_music() {
+        let data = format!("{}{}{}{}",
+            APPLE_MUSIC_HEADER,
+            "Roads,Portishead,Dummy,PLAY_START,2024-01-31T18:03:00Z,,,302000,\n",
+            "Roads,Portishead,Dummy,PLAY_END,2024-01-31T18:03:00Z,2024-01-31T18:03:40Z,40000,302000,TRACK_SKIPPED_FORWARDS\n",
+            "Teardrop,Massive Attack,,PLAY_END,,2024-01-31T18:10:00Z,330000,330000,NATURAL_END_OF_TRACK\n");
+        let parsed = parse(data.as_bytes(), ImportFormat::AppleMusic).unwrap();
+        assert_eq!(parsed.skipped, 1);
+
+        let roads = &parsed.plays[0];
+        assert_eq!(roads.started_at, 1_706_724_180_000);
+        assert_eq!((roads.duration, roads.played_secs), (302.0, 40.0));
+        assert_eq!(roads.ended, Some(PlayOutcome::Skipped { position: 40.0 }));
+
+        // Without a start time, the play starts its duration before it ended.
+        let teardrop = &parsed.plays[1];
+        assert_eq!(teardrop.started_at, 1_706_724_600_000 - 330_000);
+        assert_eq!(teardrop.album, UNKNOWN);
+        assert_eq!(teardrop.ended, Some(PlayOutcome::Completed));
+    }
+
+    #[test]
+    fn rejects_csv_files_that_are_not_apple_music_exports() {
+        assert!(parse(b"artist,track\nPortishead,Roads\n", ImportFormat::AppleMusic).is_err());
+    }
+
+    #[test]
+    fn applies_the_history_privacy_rules_to_imports() {
+        let rules: Vec<crate::config::PrivacyRuleConfig> = vec![
+            toml::from_str("artist = \"^Portishead$\"\naction = \"hide\"\nsinks = [\"history\"]").unwrap(),
+            toml::from_str("title = \"Teardrop\"\naction = \"redact\"\nredact = [\"title\"]").unwrap(),
+        ];
+        let privacy = PrivacyFilter::new(&rules).unwrap();
+        let data = b"Portishead,Dummy,Roads,1706724180\nMassive Attack,Mezzanine,Teardrop,1706724480\n";
+
+        let parsed = parse_visible(data, ImportFormat::Lastfm, &privacy).unwrap();
+        assert_eq!(parsed.skipped, 1);
+        assert_eq!(parsed.plays.len(), 1);
+        assert_eq!((parsed.plays[0].track_name.as_str(), parsed.plays[0].album.as_str()), (UNKNOWN, "Mezzanine"));
+    }
+}
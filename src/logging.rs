@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use tracing_appender::{non_blocking::WorkerGuard, rolling::{RollingFileAppender, Rotation}};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry};
+
+use crate::models::{Args, LogFormat, LogRotation};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Initialises the global tracing subscriber from the command line arguments.
+///
+/// The returned guard flushes the log file on drop and must be kept alive for
+/// the lifetime of the program.
+pub fn init(args: &Args) -> Option<WorkerGuard> {
+    let filter = EnvFilter::try_new(&args.log_level).unwrap_or_else(|e| {
+        eprintln!("Invalid log filter '{}' ({}); falling back to 'info'", args.log_level, e);
+        EnvFilter::new("info")
+    });
+
+    // Subcommands may write their output to stdout, so keep logs out of it.
+    let console = match args.command {
+        Some(_) => format_layer(fmt::layer().with_writer(std::io::stderr), args.log_format),
+        None => format_layer(fmt::layer(), args.log_format),
+    };
+    let mut layers: Vec<BoxedLayer> = vec![console];
+
+    let guard = match &args.log_file {
+        Some(path) => match file_appender(path, args.log_rotation, args.log_max_files) {
+            Ok(appender) => {
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                layers.push(format_layer(fmt::layer().with_ansi(false).with_writer(writer), args.log_format));
+                Some(guard)
+            }
+            Err(e) => {
+                eprintln!("Failed to open log file {}: {}", path.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .init();
+
+    guard
+}
+
+fn format_layer<W>(layer: fmt::Layer<Registry, fmt::format::DefaultFields, fmt::format::Format, W>, format: LogFormat) -> BoxedLayer
+where
+    W: for<'writer> fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = layer.with_thread_ids(true).with_thread_names(true);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+fn file_appender(path: &Path, rotation: LogRotation, max_files: usize) -> Result<RollingFileAppender, tracing_appender::rolling::InitError> {
+    let directory = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let prefix = path.file_stem().and_then(|s| s.to_str()).unwrap_or("rusty-tapes");
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        })
+        .filename_prefix(prefix)
+        .max_log_files(max_files);
+
+    if let Some(extension) = path.extension().and_then(|s| s.to_str()) {
+        builder = builder.filename_suffix(extension);
+    }
+
+    builder.build(directory)
+}
@@ -1,30 +1,58 @@
-use std::{sync::{atomic::{self, Ordering}, Arc, Mutex}};
-use axum::{body::Body, extract::{ws::WebSocket, State, WebSocketUpgrade}, http::StatusCode, response::Response, routing::any, Json, Router};
-use tokio::sync::broadcast;
+use std::{sync::{atomic::Ordering, Arc}};
+use clap::Parser;
+use axum::{body::Body, extract::{ws::WebSocket, Path, Query, State, WebSocketUpgrade}, http::StatusCode, middleware, response::Response, routing::{any, get}, Json, Router};
+use tokio::sync::mpsc;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{info, warn};
 use futures_util::{sink::SinkExt, stream::{StreamExt, SplitSink, SplitStream}};
 
+mod auth;
+mod config;
+mod fanout;
+mod history;
+mod history_io;
+mod logging;
+mod metrics;
 mod models;
+mod mqtt;
+mod playback;
+mod privacy;
+mod session;
+mod sources;
+mod sse;
+mod templates;
+mod text_output;
 mod utils;
+mod webhooks;
 
-use crate::models::AppState;
+use crate::{config::PrivacySink, models::AppState, sources::SourceStatus};
 
-async fn socket_handler(mut socket: WebSocket, state: Arc<AppState>) {
+const SOURCE_READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(serde::Deserialize)]
+struct WsParams {
+    /// Only receive updates from this source instead of the selected one
+    source: Option<String>,
+}
+
+async fn socket_handler(socket: WebSocket, state: Arc<AppState>, source: Option<String>) {
     let connection_count = state.active_connections.fetch_add(1, Ordering::Relaxed);
-    info!("New client connection. Total: {}", connection_count + 1);
+    info!("New client connection (source: {:?}). Total: {}", source, connection_count + 1);
 
     let (sender, receiver) = socket.split();
-    let mut message_receiver = state.client_sender.subscribe();
 
-    let state_clone = state.clone();
     let reader_handle = tokio::spawn(async move {
-        reader_client_task(receiver, state_clone).await;
+        reader_client_task(receiver).await;
     });
 
-    let writer_handle = tokio::spawn(async move {
-        writer_client_task(sender, message_receiver).await;
-    });
+    let (initial, frames) = match &source {
+        Some(source) => {
+            let (initial, frames) = state.subscribe_source(source);
+            (initial.into_iter().collect(), frames)
+        }
+        None => state.subscribe_from(None),
+    };
+    let writer_handle = tokio::spawn(frame_writer_task(sender, initial, frames));
 
     tokio::select! {
         _ = reader_handle => {
@@ -39,11 +67,11 @@ async fn socket_handler(mut socket: WebSocket, state: Arc<AppState>) {
     info!("Client connection closed. Total: {}", final_count - 1);
 }
 
-async fn reader_client_task(mut receiver: SplitStream<WebSocket>, state: Arc<AppState>) {
+async fn reader_client_task(mut receiver: SplitStream<WebSocket>) {
     while let Some(msg) = receiver.next().await {
         match msg {
             Ok(msg) => {
-                info!("Received client message: {:?}", msg);
+                info!(target: "rusty_tapes::ws_reader", "Received client message: {:?}", msg);
             }
             Err(e) => {
                 warn!("Error receiving client message: {:?}", e);
@@ -53,74 +81,181 @@ async fn reader_client_task(mut receiver: SplitStream<WebSocket>, state: Arc<App
     }
 }
 
-async fn writer_client_task(mut sender: SplitSink<WebSocket, axum::extract::ws::Message>, mut message_receiver: broadcast::Receiver<models::TrackInfo>) {
-    while let Ok(chat_message) = message_receiver.recv().await {
-        let msg_text = serde_json::to_string(&chat_message).unwrap_or_else(|_| "{}".to_string());
-        if sender.send(axum::extract::ws::Message::Text(msg_text.into())).await.is_err() {
+/// Sends the initial state, then forwards pre-serialised frames; the hub
+/// closes `frames` if this client falls too far behind.
+async fn frame_writer_task(mut sender: SplitSink<WebSocket, axum::extract::ws::Message>, initial: Vec<fanout::Frame>, mut frames: mpsc::Receiver<fanout::Frame>) {
+    for frame in initial {
+        if sender.send(axum::extract::ws::Message::Text(frame.json)).await.is_err() {
+            warn!("Error sending client message");
+            return;
+        }
+    }
+    while let Some(frame) = frames.recv().await {
+        if sender.send(axum::extract::ws::Message::Text(frame.json)).await.is_err() {
             warn!("Error sending client message");
             break;
         }
     }
 }
 
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> axum::response::Response {
-    ws.on_upgrade(|socket| socket_handler(socket, state))
+async fn ws_handler(ws: WebSocketUpgrade, Query(params): Query<WsParams>, State(state): State<Arc<AppState>>) -> axum::response::Response {
+    ws.on_upgrade(|socket| socket_handler(socket, state, params.source))
+}
+
+async fn list_sources(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let sources: Vec<SourceStatus> = state.sources.lock().unwrap().values().map(|source| visible_source(&state, source)).collect();
+    let active = sources.iter().find(|source| source.active).map(|source| source.name.clone());
+    let response = serde_json::json!({ "active": active, "sources": sources });
+    (StatusCode::OK, Json(response))
+}
+
+async fn get_source(Path(name): Path<String>, State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    match state.sources.lock().unwrap().get(&name) {
+        Some(source) => (StatusCode::OK, Json(serde_json::json!(visible_source(&state, source)))),
+        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": format!("Unknown source '{}'", name) }))),
+    }
+}
+
+/// A source's snapshot with its track passed through the API privacy rules.
+fn visible_source(state: &AppState, source: &SourceStatus) -> SourceStatus {
+    let snapshot = source.snapshot();
+    SourceStatus {
+        track: snapshot.track.as_ref().and_then(|track| state.privacy.filter_track(PrivacySink::Api, track)),
+        ..snapshot
+    }
 }
 
 async fn is_playing_check(State(state): State<Arc<AppState>>,) -> (StatusCode, Json<serde_json::Value>) {
-    let is_playing = state.is_playing.load(Ordering::Relaxed);
-    let response = serde_json::json!({ "is_playing": is_playing });
+    let playback = state.playback();
+    let response = serde_json::json!({ "is_playing": playback.playing, "state": playback.state });
     (StatusCode::OK, Json(response))
 }
 
 async fn get_last_track(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
-    let last_track = state.last_track_info.lock().unwrap();
-    let response = match &*last_track {
-        Some(track) => serde_json::json!({
-            "track": track,
-        }),
-        None => serde_json::json!({ "track": null }),
-    };
+    let now = std::time::SystemTime::now();
+    let playback = state.playback();
+    let response = serde_json::json!({
+        "seq": playback.seq,
+        "track": state.visible_track(&playback, now),
+        "server_time": utils::rfc3339(now),
+    });
+    (StatusCode::OK, Json(response))
+}
+
+async fn player_state(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let now = std::time::SystemTime::now();
+    let playback = state.playback();
+    let source = state.sources.lock().unwrap().values().find(|source| source.active).map(|source| source.name.clone());
+    let response = serde_json::json!({
+        "seq": playback.seq,
+        "state": playback.state,
+        "since": utils::rfc3339(playback.state_since),
+        "source": source,
+        "track": state.visible_track(&playback, now),
+        "server_time": utils::rfc3339(now),
+    });
     (StatusCode::OK, Json(response))
 }
 
 async fn last_update(State(state): State<Arc<AppState>>,) -> (StatusCode, Json<serde_json::Value>) {
-    let last_update = state.last_update.lock().unwrap();
-    let response = serde_json::json!({ "last_update": last_update.elapsed().as_secs() });
+    let response = serde_json::json!({
+        "last_update": utils::rfc3339(state.playback().updated_at),
+        "server_time": utils::rfc3339(std::time::SystemTime::now()),
+    });
     (StatusCode::OK, Json(response))
 }
 
+async fn healthz() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::OK, Json(serde_json::json!({ "status": "ok" })))
+}
+
+async fn readyz(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let sources = state.metrics.reachable_sources(SOURCE_READY_TIMEOUT);
+    let source_reachable = sources.values().any(|reachable| *reachable);
+    let response = serde_json::json!({
+        "ready": source_reachable,
+        "source_reachable": source_reachable,
+        "sources": sources,
+        "integrations": state.metrics.integrations(),
+    });
+    let status = if source_reachable { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(response))
+}
+
+async fn prometheus_metrics(State(state): State<Arc<AppState>>) -> Response {
+    let body = state.metrics.render(state.active_connections.load(Ordering::Relaxed));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(body))
+        .unwrap()
+}
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .init();
+    let args = models::Args::parse();
+    let _log_guard = logging::init(&args);
+    let args = utils::normalize_args(args);
+    let config = args.config.as_deref()
+        .map(|path| config::Config::load(path).expect("Failed to load config file"))
+        .unwrap_or_default();
+
+    if let Some(command) = &args.command {
+        let result = match command {
+            models::Command::Export { format, output } => history_io::export_to(*format, output.as_deref(), &config.history),
+            models::Command::Import { format, file } => history_io::import_from(*format, file, &config),
+            models::Command::Session { action } => session::run(action, &args, &config.sessions).await,
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     const CLIENT_ID: &str = "1400478980259315843";
 
-    let state = Arc::new(AppState {
-        client_sender: {
-            let (tx, _rx) = tokio::sync::broadcast::channel(100);
-            tx
-        },
-        active_connections: atomic::AtomicUsize::new(0),
-        last_track_info: Mutex::new(None),
-        last_update: Mutex::new(std::time::Instant::now()),
-        is_playing: atomic::AtomicBool::new(false),
-        scrobble_sent: atomic::AtomicBool::new(false),
+    if args.sources.is_empty() {
+        eprintln!("No player source given; pass e.g. --source ingest:<name>, or --source mock for fake plays");
+        std::process::exit(2);
+    }
 
-    });
+    let state = Arc::new(AppState::new(&config, args.api_token.clone()).expect("Failed to initialise the server"));
 
-    utils::listen_for_track(state.clone());
+    let source_routes = sources::start_sources(state.clone(), &args.sources, args.source_policy, &config)
+        .expect("Failed to start player sources");
 
-    utils::discord_rpc_task(state.clone(), CLIENT_ID);
+    let discord_client_id = config.discord.client_id.clone().unwrap_or_else(|| CLIENT_ID.to_string());
+    utils::discord_rpc_task(state.clone(), &discord_client_id, config.discord.clone());
+
+    if let Some(output_dir) = &args.text_output_dir {
+        text_output::text_output_task(state.clone(), text_output::TextOutputConfig::from_args(output_dir, &args));
+    }
+
+    if !config.webhooks.is_empty() {
+        webhooks::webhook_task(state.clone(), config.webhooks.clone(), config.webhook_dead_letter_file.clone());
+    }
+
+    if let Some(mqtt_config) = &config.mqtt {
+        mqtt::mqtt_task(state.clone(), mqtt_config.clone());
+    }
 
     let app = Router::new()
         .route("/api/ws", any(ws_handler))
         .route("/api/is_playing", any(is_playing_check))
+        .route("/api/state", any(player_state))
         .route("/api/last_track", any(get_last_track))
         .route("/api/last_update", any(last_update))
+        .route("/api/events", get(sse::events_handler))
+        .route("/api/sources", get(list_sources))
+        .route("/api/sources/{name}", get(get_source))
+        .route("/healthz", any(healthz))
+        .route("/readyz", any(readyz))
+        .route("/metrics", any(prometheus_metrics))
+        .merge(source_routes)
+        .merge(history::routes())
+        .merge(history_io::routes())
+        .merge(session::routes())
         .route("/overlay", any(|| async {
             Response::builder()
                 .status(StatusCode::OK)
@@ -135,6 +270,14 @@ async fn main() {
                 .body(Body::from(include_str!("../static/overlay-scroll.html")))
                 .unwrap()
         }))
+        .route("/dashboard", any(|| async {
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/html")
+                .body(Body::from(include_str!("../static/dashboard.html")))
+                .unwrap()
+        }))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::require_api_token))
         .layer(
             CorsLayer::new()
                 .allow_methods(Any)
@@ -143,11 +286,9 @@ async fn main() {
         )
         .with_state(state);
 
-    let args = utils::parse_args();
-
     info!("Server listening on http://{}:{}", args.host, args.port);
     let listener = tokio::net::TcpListener::bind((args.host, args.port)).await
         .expect("Failed to bind TCP listener");
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await.unwrap();
 }
\ No newline at end of file
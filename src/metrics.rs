@@ -0,0 +1,236 @@
+use std::{collections::BTreeMap, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, Instant}};
+
+const POLL_LATENCY_BUCKETS: [f64; 9] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+pub struct Histogram {
+    buckets: [AtomicU64; POLL_LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(POLL_LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bucket, bound) in self.buckets.iter().zip(POLL_LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed));
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// When a source last completed a poll, and the delay it chose before the
+/// next one.
+#[derive(Clone, Copy, Debug, Default)]
+struct SourcePolls {
+    last_poll: Option<Instant>,
+    interval: Duration,
+}
+
+impl SourcePolls {
+    fn reachable(&self, max_age: Duration) -> bool {
+        self.last_poll.is_some_and(|last_poll| last_poll.elapsed() <= max_age + self.interval)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct IntegrationStatus {
+    pub connected: bool,
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+pub struct Metrics {
+    pub broadcast_lag_drops: AtomicU64,
+    pub slow_client_evictions: AtomicU64,
+    pub track_changes: AtomicU64,
+    pub poll_latency: Histogram,
+    pub artwork_hits: AtomicU64,
+    pub artwork_misses: AtomicU64,
+    /// Lookups that failed before getting an answer, e.g. network errors
+    pub artwork_errors: AtomicU64,
+    source_polls: Mutex<BTreeMap<String, SourcePolls>>,
+    pub integrations: Mutex<BTreeMap<&'static str, IntegrationStatus>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            broadcast_lag_drops: AtomicU64::new(0),
+            slow_client_evictions: AtomicU64::new(0),
+            track_changes: AtomicU64::new(0),
+            poll_latency: Histogram::new(),
+            artwork_hits: AtomicU64::new(0),
+            artwork_misses: AtomicU64::new(0),
+            artwork_errors: AtomicU64::new(0),
+            source_polls: Mutex::new(BTreeMap::new()),
+            integrations: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_poll(&self, source: &str, latency: Duration) {
+        self.poll_latency.observe(latency);
+        self.source_polls.lock().unwrap().entry(source.to_string()).or_default().last_poll = Some(Instant::now());
+    }
+
+    /// Records an update pushed by `source`, which keeps it reachable like a
+    /// completed poll but has no latency to observe.
+    pub fn record_push(&self, source: &str) {
+        self.source_polls.lock().unwrap().entry(source.to_string()).or_default().last_poll = Some(Instant::now());
+    }
+
+    /// Records the delay `source` chose before its next poll.
+    pub fn record_poll_interval(&self, source: &str, interval: Duration) {
+        self.source_polls.lock().unwrap().entry(source.to_string()).or_default().interval = interval;
+    }
+
+    /// Whether each polled source has completed a poll within `max_age` of
+    /// when it was due.
+    pub fn reachable_sources(&self, max_age: Duration) -> BTreeMap<String, bool> {
+        self.source_polls.lock().unwrap().iter()
+            .map(|(source, polls)| (source.clone(), polls.reachable(max_age)))
+            .collect()
+    }
+
+    pub fn set_integration_connected(&self, integration: &'static str, connected: bool) {
+        let mut integrations = self.integrations.lock().unwrap();
+        let status = integrations.entry(integration).or_insert_with(|| IntegrationStatus {
+            connected,
+            errors: 0,
+            last_error: None,
+        });
+        status.connected = connected;
+    }
+
+    pub fn record_integration_error(&self, integration: &'static str, error: impl ToString) {
+        let mut integrations = self.integrations.lock().unwrap();
+        let status = integrations.entry(integration).or_insert_with(|| IntegrationStatus {
+            connected: false,
+            errors: 0,
+            last_error: None,
+        });
+        status.errors += 1;
+        status.last_error = Some(error.to_string());
+    }
+
+    pub fn integrations(&self) -> BTreeMap<&'static str, IntegrationStatus> {
+        self.integrations.lock().unwrap().clone()
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self, connected_clients: usize) -> String {
+        let mut out = String::new();
+
+        render_value(&mut out, "rusty_tapes_connected_clients", "gauge",
+            "Number of currently connected WebSocket and SSE clients.", connected_clients as u64);
+        render_value(&mut out, "rusty_tapes_broadcast_lag_drops_total", "counter",
+            "Messages dropped because a subscriber lagged behind the broadcast channel.",
+            self.broadcast_lag_drops.load(Ordering::Relaxed));
+        render_value(&mut out, "rusty_tapes_slow_client_evictions_total", "counter",
+            "API clients disconnected because their event queue was full.",
+            self.slow_client_evictions.load(Ordering::Relaxed));
+        render_value(&mut out, "rusty_tapes_track_changes_total", "counter",
+            "Number of track changes observed from the player.",
+            self.track_changes.load(Ordering::Relaxed));
+        self.poll_latency.render(&mut out, "rusty_tapes_poll_latency_seconds",
+            "Time taken to query the player for its current state.");
+        let _ = writeln!(out, "# HELP rusty_tapes_poll_interval_seconds Delay before each source next polls its player.");
+        let _ = writeln!(out, "# TYPE rusty_tapes_poll_interval_seconds gauge");
+        for (source, polls) in self.source_polls.lock().unwrap().iter() {
+            let _ = writeln!(out, "rusty_tapes_poll_interval_seconds{{source=\"{}\"}} {}", escape_label(source), polls.interval.as_secs_f64());
+        }
+
+        let _ = writeln!(out, "# HELP rusty_tapes_artwork_lookups_total Artwork lookups by result.");
+        let _ = writeln!(out, "# TYPE rusty_tapes_artwork_lookups_total counter");
+        let _ = writeln!(out, "rusty_tapes_artwork_lookups_total{{result=\"hit\"}} {}", self.artwork_hits.load(Ordering::Relaxed));
+        let _ = writeln!(out, "rusty_tapes_artwork_lookups_total{{result=\"miss\"}} {}", self.artwork_misses.load(Ordering::Relaxed));
+        let _ = writeln!(out, "rusty_tapes_artwork_lookups_total{{result=\"error\"}} {}", self.artwork_errors.load(Ordering::Relaxed));
+
+        let integrations = self.integrations();
+        let _ = writeln!(out, "# HELP rusty_tapes_integration_errors_total Errors reported by each integration.");
+        let _ = writeln!(out, "# TYPE rusty_tapes_integration_errors_total counter");
+        for (name, status) in &integrations {
+            let _ = writeln!(out, "rusty_tapes_integration_errors_total{{integration=\"{}\"}} {}", name, status.errors);
+        }
+        let _ = writeln!(out, "# HELP rusty_tapes_integration_connected Whether each integration is currently connected.");
+        let _ = writeln!(out, "# TYPE rusty_tapes_integration_connected gauge");
+        for (name, status) in &integrations {
+            let _ = writeln!(out, "rusty_tapes_integration_connected{{integration=\"{}\"}} {}", name, status.connected as u8);
+        }
+
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Escapes a label value as required by the exposition format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_value(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_reachability_per_source() {
+        let metrics = Metrics::new();
+        metrics.record_poll("mpd", Duration::from_millis(3));
+        metrics.record_poll_interval("mpd", Duration::from_secs(1));
+        metrics.record_poll_interval("spotify_web", Duration::from_secs(5));
+
+        let reachable = metrics.reachable_sources(Duration::from_secs(5));
+        assert_eq!(reachable.get("mpd"), Some(&true));
+        // Chose an interval but never completed a poll.
+        assert_eq!(reachable.get("spotify_web"), Some(&false));
+
+        let rendered = metrics.render(0);
+        assert!(rendered.contains("rusty_tapes_poll_interval_seconds{source=\"mpd\"} 1\n"));
+        assert!(rendered.contains("rusty_tapes_poll_interval_seconds{source=\"spotify_web\"} 5\n"));
+    }
+
+    #[test]
+    fn stale_sources_are_unreachable() {
+        let polls = SourcePolls { last_poll: Some(Instant::now() - Duration::from_secs(10)), interval: Duration::from_secs(2) };
+        assert!(!polls.reachable(Duration::from_secs(5)));
+        assert!(polls.reachable(Duration::from_secs(9)));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
@@ -1,18 +1,8 @@
-use std::sync::{atomic, Mutex};
+use std::{collections::{BTreeMap, HashMap, VecDeque}, path::PathBuf, sync::{atomic, Arc, Mutex}, time::SystemTime};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
-#[repr(C)]
-pub struct TrackInfoC {
-    pub track_name: *const std::os::raw::c_char,
-    pub artist_name: *const std::os::raw::c_char,
-    pub progress: f64,
-    pub duration: f32,
-    pub genre: *const std::os::raw::c_char,
-    pub favourited: bool,
-    pub played_count: i32,
-    pub album: *const std::os::raw::c_char,
-}
+use crate::{config::{Config, PrivacySink}, fanout::{ClientHub, Frame}, history::PlayHistory, history_io::{ExportFormat, ImportFormat}, metrics::Metrics, playback::{PlayOutcome, Transition}, privacy::PrivacyFilter, session::{SessionRecorder, TracklistFormat}, sources::{self, SourceStatus}, utils};
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct TrackInfo {
@@ -24,15 +14,461 @@ pub struct TrackInfo {
     pub favourited: bool,
     pub played_count: i32,
     pub album: String,
+    /// The name of the source that reported this track
+    #[serde(default)]
+    pub source: String,
+    /// Cover artwork reported by the source itself, if it provides one. An
+    /// empty URL marks artwork withheld by a privacy rule.
+    #[serde(default, skip_serializing_if = "artwork_withheld")]
+    pub artwork_url: Option<String>,
+    /// Server-side timing, set when the track is published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<PlaybackTiming>,
+    /// The player's state when the track was published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<PlayerState>,
+}
+
+/// Wall-clock anchors for `progress`, as Unix times in milliseconds, so that
+/// clients compute the position as `progress + (now - position_at) * rate`
+/// instead of timing it themselves.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PlaybackTiming {
+    /// When `progress` was measured by the source
+    pub position_at: u64,
+    /// When the track would have started, playing continuously at `rate`
+    pub started_at: u64,
+    /// 1.0 while playing, 0.0 while paused
+    pub rate: f64,
+    /// When the server published this, to correct for client clock skew
+    pub server_time: u64,
+}
+
+/// What a player is doing, as far as its source can tell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerState {
+    Playing,
+    Paused,
+    /// Running, but with no current track
+    #[default]
+    Stopped,
+    /// The player application is not running
+    NotRunning,
+    /// The player can't be reached or refused access, e.g. a missing
+    /// automation permission or login
+    Unavailable,
+}
+
+impl PlayerState {
+    pub fn is_playing(self) -> bool {
+        self == PlayerState::Playing
+    }
+
+    /// Whether the player still has a current track in this state.
+    pub fn has_track(self) -> bool {
+        matches!(self, PlayerState::Playing | PlayerState::Paused)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PlayerState::Playing => "playing",
+            PlayerState::Paused => "paused",
+            PlayerState::Stopped => "stopped",
+            PlayerState::NotRunning => "not_running",
+            PlayerState::Unavailable => "unavailable",
+        }
+    }
+}
+
+fn artwork_withheld(artwork_url: &Option<String>) -> bool {
+    artwork_url.as_deref().is_none_or(str::is_empty)
+}
+
+#[cfg(test)]
+impl TrackInfo {
+    /// A playing track from the `test` source, for tests.
+    pub fn test(track_name: &str, artist_name: &str, duration: f32, progress: f64) -> Self {
+        TrackInfo {
+            track_name: track_name.to_string(),
+            artist_name: artist_name.to_string(),
+            progress,
+            duration,
+            genre: "Unknown".to_string(),
+            favourited: false,
+            played_count: 0,
+            album: "Unknown".to_string(),
+            source: "test".to_string(),
+            artwork_url: None,
+            timing: None,
+            state: None,
+        }
+    }
+}
+
+impl TrackInfo {
+    /// Pause updates are sent with the last track and a negative duration.
+    pub fn is_paused(&self) -> bool {
+        self.duration < 0.0
+    }
+
+    /// Whether this update reports a player that stopped, quit or became
+    /// unavailable, rather than one that merely paused.
+    pub fn is_stopped(&self) -> bool {
+        self.is_paused() && self.state.is_some_and(|state| !state.has_track())
+    }
+
+    /// Stamps the track with timing for its `progress` measured at `position_at`.
+    pub fn with_timing(mut self, position_at: SystemTime, rate: f64) -> Self {
+        let position_at = utils::unix_millis(position_at);
+        self.timing = Some(PlaybackTiming {
+            position_at,
+            started_at: position_at.saturating_sub((self.progress.max(0.0) * rate * 1000.0) as u64),
+            rate,
+            server_time: utils::unix_millis(SystemTime::now()),
+        });
+        self
+    }
+
+    /// The position extrapolated to `now` from the track's timing.
+    pub fn position_at(&self, now: SystemTime) -> f64 {
+        match &self.timing {
+            Some(timing) => {
+                let elapsed = utils::unix_millis(now).saturating_sub(timing.position_at) as f64 / 1000.0;
+                let position = self.progress + elapsed * timing.rate;
+                if self.duration > 0.0 { position.min(self.duration as f64) } else { position }
+            }
+            None => self.progress,
+        }
+    }
+}
+
+/// The kind of playback change a track update represents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackEventKind {
+    TrackChanged,
+    Paused,
+    Resumed,
+    Seeked,
+    /// The current track started over from the beginning
+    Repeated,
+    /// The player stopped, quit or became unavailable
+    Stopped,
+}
+
+impl TrackEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackEventKind::TrackChanged => "track_changed",
+            TrackEventKind::Paused => "paused",
+            TrackEventKind::Resumed => "resumed",
+            TrackEventKind::Seeked => "seeked",
+            TrackEventKind::Repeated => "repeated",
+            TrackEventKind::Stopped => "stopped",
+        }
+    }
+
+    /// The kind of event a playback transition is published as.
+    pub fn of(transition: &Transition) -> Self {
+        match transition {
+            Transition::Started(_) | Transition::Changed(..) => TrackEventKind::TrackChanged,
+            Transition::Paused(_) => TrackEventKind::Paused,
+            Transition::Resumed(_) => TrackEventKind::Resumed,
+            Transition::Seeked(_) => TrackEventKind::Seeked,
+            Transition::Repeated(_) => TrackEventKind::Repeated,
+            Transition::Stopped(..) => TrackEventKind::Stopped,
+        }
+    }
+}
+
+/// A playback control command routed to the player source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlayerCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    Seek(f64),
+}
+
+impl PlayerCommand {
+    /// Parses commands such as `play`, `next` or `seek:42.5`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        let command = match value.as_str() {
+            "play" => PlayerCommand::Play,
+            "pause" => PlayerCommand::Pause,
+            "play_pause" | "playpause" | "toggle" => PlayerCommand::PlayPause,
+            "next" => PlayerCommand::Next,
+            "previous" | "prev" => PlayerCommand::Previous,
+            _ => {
+                let position = value.strip_prefix("seek:")?.trim().parse::<f64>().ok()?;
+                PlayerCommand::Seek(position.max(0.0))
+            }
+        };
+        Some(command)
+    }
+}
+
+/// A track update as published to clients, numbered so that SSE clients can
+/// resume with `Last-Event-ID`.
+#[derive(Clone, Debug)]
+pub struct TrackEvent {
+    pub id: u64,
+    /// The `PlaybackSnapshot::seq` this event was published with
+    pub seq: u64,
+    pub track: TrackInfo,
+    /// The playback transition this event publishes
+    pub kind: TrackEventKind,
+    /// The privacy rules matching `track`, evaluated once when broadcast
+    pub privacy_matches: Arc<[usize]>,
+    /// `track` as API clients may see it, serialised once; `None` if hidden
+    pub api_json: Option<axum::extract::ws::Utf8Bytes>,
+    /// How the previous play ended, if this event ended one
+    pub ended: Option<PlayOutcome>,
+}
+
+impl TrackEvent {
+    pub fn frame(&self) -> Option<Frame> {
+        self.api_json.clone().map(|json| Frame { id: self.id, json })
+    }
+}
+
+pub const EVENT_HISTORY_CAPACITY: usize = 100;
+
+/// One consistent view of the selected playback, replaced as a whole on
+/// every change and published through `AppState::playback`.
+#[derive(Clone, Debug)]
+pub struct PlaybackSnapshot {
+    /// Incremented on every update
+    pub seq: u64,
+    /// The last track shown, kept while paused
+    pub track: Option<TrackInfo>,
+    pub playing: bool,
+    /// When a track last started, resumed or seeked
+    pub updated_at: SystemTime,
+    /// The selected player's state
+    pub state: PlayerState,
+    /// When `state` last changed
+    pub state_since: SystemTime,
+}
+
+impl PlaybackSnapshot {
+    pub fn new() -> Self {
+        let now = SystemTime::now();
+        PlaybackSnapshot { seq: 0, track: None, playing: false, updated_at: now, state: PlayerState::default(), state_since: now }
+    }
 }
 
 pub struct AppState {
-    pub client_sender: tokio::sync::broadcast::Sender<TrackInfo>,
+    /// Track events for the internal sinks (Discord, webhooks, MQTT, ...)
+    pub client_sender: tokio::sync::broadcast::Sender<Arc<TrackEvent>>,
+    pub event_history: Mutex<VecDeque<Arc<TrackEvent>>>,
+    /// Pre-serialised track events for WebSocket and SSE clients
+    pub clients: ClientHub,
+    /// Connected WebSocket and SSE clients
     pub active_connections: atomic::AtomicUsize,
-    pub last_track_info: Mutex<Option<TrackInfo>>,
-    pub last_update: Mutex<std::time::Instant>,
-    pub is_playing: atomic::AtomicBool,
-    pub scrobble_sent: atomic::AtomicBool,
+    pub playback: tokio::sync::watch::Sender<Arc<PlaybackSnapshot>>,
+    pub metrics: Metrics,
+    pub artwork_cache: Mutex<HashMap<String, Option<String>>>,
+    pub sources: Mutex<BTreeMap<String, SourceStatus>>,
+    pub source_commands: Mutex<HashMap<String, tokio::sync::mpsc::UnboundedSender<PlayerCommand>>>,
+    pub api_token: Option<String>,
+    pub privacy: PrivacyFilter,
+    pub history: PlayHistory,
+    pub session: SessionRecorder,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A server state without a play history on disk, for tests.
+    pub fn test() -> Arc<Self> {
+        let mut config = Config::default();
+        config.history.enabled = false;
+        Arc::new(AppState::new(&config, None).expect("valid default config"))
+    }
+}
+
+impl AppState {
+    pub fn new(config: &Config, api_token: Option<String>) -> Result<Self, String> {
+        Ok(AppState {
+            client_sender: tokio::sync::broadcast::channel(EVENT_HISTORY_CAPACITY).0,
+            event_history: Mutex::new(VecDeque::with_capacity(EVENT_HISTORY_CAPACITY)),
+            clients: ClientHub::new(),
+            active_connections: atomic::AtomicUsize::new(0),
+            playback: tokio::sync::watch::Sender::new(Arc::new(PlaybackSnapshot::new())),
+            metrics: Metrics::new(),
+            artwork_cache: Mutex::new(HashMap::new()),
+            sources: Mutex::new(BTreeMap::new()),
+            source_commands: Mutex::new(HashMap::new()),
+            api_token,
+            privacy: PrivacyFilter::new(&config.privacy)?,
+            history: PlayHistory::open(&config.history)?,
+            session: SessionRecorder::new(&config.sessions),
+        })
+    }
+
+    /// The current playback snapshot.
+    pub fn playback(&self) -> Arc<PlaybackSnapshot> {
+        self.playback.borrow().clone()
+    }
+
+    /// Publishes a new snapshot derived from the current one, with the next
+    /// sequence number, and returns that number.
+    pub fn update_playback(&self, update: impl FnOnce(&mut PlaybackSnapshot)) -> u64 {
+        let mut seq = 0;
+        self.playback.send_modify(|current| {
+            let mut next = (**current).clone();
+            update(&mut next);
+            next.seq = current.seq + 1;
+            if next.state != current.state {
+                next.state_since = SystemTime::now();
+            }
+            seq = next.seq;
+            *current = Arc::new(next);
+        });
+        seq
+    }
+
+    /// Applies `update` to the playback snapshot, then assigns the next event
+    /// id to `track`, records it in the replay history and sends it to all
+    /// subscribers. Both happen under the history lock, so a subscriber never
+    /// sees a snapshot without its event or the other way round.
+    pub fn broadcast(&self, track: TrackInfo, kind: TrackEventKind, ended: Option<PlayOutcome>, update: impl FnOnce(&mut PlaybackSnapshot)) {
+        let mut history = self.event_history.lock().unwrap();
+        let seq = self.update_playback(update);
+        let id = history.back().map(|event| event.id + 1).unwrap_or(1);
+        let privacy_matches = self.privacy.matching(&track);
+        let mut event = TrackEvent { id, seq, track, kind, privacy_matches, api_json: None, ended };
+        event.api_json = self.privacy.filter_event(PrivacySink::Api, &event)
+            .map(|visible| api_json(&visible.track, seq, ended));
+        let event = Arc::new(event);
+
+        if history.len() == EVENT_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        if let Some(frame) = event.frame() {
+            self.clients.publish(None, &frame, &self.metrics);
+        }
+        let _ = self.client_sender.send(event);
+    }
+
+    /// Records `transition` in the play log and any stream session, as far as
+    /// the privacy rules allow.
+    pub fn record_play(&self, transition: &Transition, at: SystemTime) {
+        self.history.record(transition, at, |track| self.privacy.filter_track(PrivacySink::History, track));
+        self.session.record(transition, at, |track| self.privacy.filter_track(PrivacySink::Sessions, track));
+    }
+
+    /// Starts a stream session, including the track playing right now.
+    pub fn start_session(&self, name: Option<String>, at: SystemTime) -> Result<(), String> {
+        self.session.start(name, at)?;
+        let playback = self.playback();
+        if let Some(track) = playback.track.as_ref().filter(|_| playback.playing) {
+            self.session.record(&Transition::Started(track.clone()), at, |track| self.privacy.filter_track(PrivacySink::Sessions, track));
+        }
+        Ok(())
+    }
+
+    /// Routes a control command to the active source, or to the only
+    /// registered source if none is active yet.
+    pub fn send_player_command(&self, command: PlayerCommand) -> bool {
+        let target = {
+            let sources = self.sources.lock().unwrap();
+            sources.values().find(|source| source.active)
+                .or_else(|| if sources.len() == 1 { sources.values().next() } else { None })
+                .map(|source| source.name.clone())
+        };
+        let Some(target) = target else {
+            return false;
+        };
+        self.source_commands.lock().unwrap().get(&target)
+            .is_some_and(|sender| sender.send(command).is_ok())
+    }
+
+    /// The snapshot's track as API clients may see it, with timing as of `now`.
+    pub fn visible_track(&self, playback: &PlaybackSnapshot, now: SystemTime) -> Option<TrackInfo> {
+        playback.track.as_ref()
+            .and_then(|track| self.privacy.filter_track(PrivacySink::Api, track))
+            .map(|mut track| {
+                if let Some(timing) = &mut track.timing {
+                    timing.server_time = utils::unix_millis(now);
+                }
+                track
+            })
+    }
+
+    /// Subscribes an API client to track frames, returning the frames it has
+    /// missed since `last_event_id`, or the current playback snapshot as the
+    /// initial state if no id was given or it is no longer in the history.
+    pub fn subscribe_from(&self, last_event_id: Option<u64>) -> (Vec<Frame>, tokio::sync::mpsc::Receiver<Frame>) {
+        let history = self.event_history.lock().unwrap();
+        let receiver = self.clients.subscribe(None);
+
+        let resumable = last_event_id.filter(|last_id| {
+            history.front().is_some_and(|oldest| oldest.id <= last_id.saturating_add(1))
+                && history.back().is_some_and(|newest| newest.id >= *last_id)
+        });
+        let backlog = match resumable {
+            Some(last_id) => history.iter().filter(|event| event.id > last_id).filter_map(|event| event.frame()).collect(),
+            None => {
+                let id = history.back().map(|event| event.id).unwrap_or(0);
+                self.snapshot_frame(&self.playback(), id).into_iter().collect()
+            }
+        };
+        (backlog, receiver)
+    }
+
+    /// Subscribes an API client to a single source's frames, returning the
+    /// source's current track as the initial state.
+    pub fn subscribe_source(&self, source: &str) -> (Option<Frame>, tokio::sync::mpsc::Receiver<Frame>) {
+        // Reports are applied under this lock, so no update falls in between.
+        let sources = self.sources.lock().unwrap();
+        let receiver = self.clients.subscribe(Some(source));
+        let initial = sources.get(source).and_then(|status| {
+            let track = status.track.as_ref()?;
+            let track = if status.playing { track.clone() } else { sources::state_marker(track, status.state) };
+            self.source_frame(&track)
+        });
+        (initial, receiver)
+    }
+
+    /// Sends a source's track update to the clients following that source.
+    pub fn publish_source(&self, source: &str, track: &TrackInfo) {
+        if let Some(frame) = self.source_frame(track) {
+            self.clients.publish(Some(source), &frame, &self.metrics);
+        }
+    }
+
+    fn source_frame(&self, track: &TrackInfo) -> Option<Frame> {
+        let track = self.privacy.filter_track(PrivacySink::Api, track)?;
+        Some(Frame { id: 0, json: serde_json::to_string(&track).unwrap_or_else(|_| "{}".to_string()).into() })
+    }
+
+    /// A frame describing `playback` as the event with `id` left it; paused
+    /// and stopped players are shown with a state marker like live updates.
+    fn snapshot_frame(&self, playback: &PlaybackSnapshot, id: u64) -> Option<Frame> {
+        let track = self.visible_track(playback, SystemTime::now())?;
+        let track = match playback.playing {
+            true => track,
+            false => sources::state_marker(&track, playback.state),
+        };
+        Some(Frame { id, json: api_json(&track, playback.seq, None) })
+    }
+}
+
+/// Serialises a track for API clients, tagged with the snapshot `seq` it
+/// belongs to and, for events that ended a play, how that play ended.
+fn api_json(track: &TrackInfo, seq: u64, ended: Option<PlayOutcome>) -> axum::extract::ws::Utf8Bytes {
+    let mut json = serde_json::to_value(track).unwrap_or_else(|_| serde_json::json!({}));
+    json["seq"] = seq.into();
+    if let Some(ended) = ended {
+        json["previous_play"] = serde_json::json!(ended);
+    }
+    json.to_string().into()
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -45,4 +481,263 @@ pub struct Args {
     /// The port to bind the server to
     #[arg(short, long, default_value = "7271")]
     pub port: u16,
-}
\ No newline at end of file
+
+    /// A player source to watch, in priority order; may be repeated.
+    /// `ingest:<name>` accepts updates pushed to `/api/ingest`, and
+    /// `mpris:<player>` follows a single MPRIS player on Linux. Required on
+    /// platforms without a default player
+    #[cfg_attr(target_os = "macos", arg(long = "source", default_value = "apple_music"))]
+    #[cfg_attr(target_os = "linux", arg(long = "source", default_value = "mpris"))]
+    #[cfg_attr(not(any(target_os = "macos", target_os = "linux")), arg(long = "source"))]
+    pub sources: Vec<String>,
+
+    /// How to choose which source is shown when several are active
+    #[arg(long, value_enum, default_value_t = SourcePolicy::OnlyPlaying)]
+    pub source_policy: SourcePolicy,
+
+    /// Token required on `/api/*` requests from other machines, e.g. relays
+    #[arg(long, env = "RUSTY_TAPES_API_TOKEN")]
+    pub api_token: Option<String>,
+
+    /// Path to a TOML configuration file for webhooks and other integrations
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Directory to write now-playing text files to, for OBS "Read from file" sources
+    #[arg(long)]
+    pub text_output_dir: Option<PathBuf>,
+
+    /// A text file to write as `FILE=TEMPLATE`, e.g. `nowplaying.txt={artist} — {track}`; may be repeated
+    #[arg(long = "text-file", value_name = "FILE=TEMPLATE", value_parser = parse_text_file)]
+    pub text_files: Vec<(String, String)>,
+
+    /// File name in the text output directory to write the cover artwork to
+    #[arg(long)]
+    pub text_cover_file: Option<String>,
+
+    /// Empty the text files while playback is paused
+    #[arg(long)]
+    pub text_clear_when_paused: bool,
+
+    /// Log filter directives, e.g. `info` or `info,rusty_tapes::ws_reader=warn`
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    pub log_level: String,
+
+    /// The format to write log lines in
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Also write logs to this file, rotated according to `--log-rotation`
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// How often the log file is rotated
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+
+    /// The number of rotated log files to keep
+    #[arg(long, default_value = "7")]
+    pub log_max_files: usize,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Write the play history to a file, or to stdout
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+
+        /// The file to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Add plays from another service's export to the play history, skipping
+    /// plays that are already in it
+    Import {
+        #[arg(long, value_enum)]
+        format: ImportFormat,
+
+        file: PathBuf,
+    },
+    /// Record a stream session on the running server and get its tracklist
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SessionAction {
+    /// Start recording the tracks played from now on
+    Start {
+        /// A title for the session, used in the tracklists
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Stop recording and save the session
+    Stop,
+    /// Print the tracklist of the current or last session
+    Tracklist {
+        #[arg(long, value_enum, default_value_t = TracklistFormat::Youtube)]
+        format: TracklistFormat,
+
+        /// Leave out tracks played for less than this many seconds
+        #[arg(long)]
+        min_duration: Option<u64>,
+
+        /// A saved session file to read instead of asking the server
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourcePolicy {
+    /// The first source in priority order that has a track, playing or not
+    Priority,
+    /// The source that most recently started playing
+    MostRecent,
+    /// The first playing source in priority order, keeping the last one while nothing plays
+    OnlyPlaying,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+fn parse_text_file(value: &str) -> Result<(String, String), String> {
+    value.split_once('=')
+        .map(|(file, template)| (file.to_string(), template.to_string()))
+        .ok_or_else(|| format!("expected FILE=TEMPLATE, got '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_json(frame: &Frame) -> serde_json::Value {
+        serde_json::from_str(frame.json.as_str()).unwrap()
+    }
+
+    #[test]
+    fn events_carry_the_snapshot_seq() {
+        let state = AppState::test();
+        let mut events = state.client_sender.subscribe();
+        let track = TrackInfo::test("Roads", "Portishead", 305.0, 0.0);
+
+        state.broadcast(track.clone(), TrackEventKind::TrackChanged, None, |playback| {
+            playback.track = Some(track.clone());
+            playback.playing = true;
+        });
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.seq, state.playback().seq);
+        assert_eq!(frame_json(&event.frame().unwrap())["seq"], event.seq);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn follows_mpris_players_by_default_on_linux() {
+        assert_eq!(Args::try_parse_from(["rusty-tapes"]).unwrap().sources, ["mpris"]);
+        let args = Args::try_parse_from(["rusty-tapes", "--source", "mock", "--source", "ingest:deck"]).unwrap();
+        assert_eq!(args.sources, ["mock", "ingest:deck"]);
+    }
+
+    #[test]
+    fn events_are_classified_by_their_transition() {
+        let roads = TrackInfo::test("Roads", "Portishead", 305.0, 0.0);
+        // Tracks that privacy rules later make look alike still changed.
+        assert_eq!(TrackEventKind::of(&Transition::Changed(roads.clone(), PlayOutcome::Completed)), TrackEventKind::TrackChanged);
+        assert_eq!(TrackEventKind::of(&Transition::Seeked(roads.clone())), TrackEventKind::Seeked);
+        assert_eq!(TrackEventKind::of(&Transition::Repeated(roads.clone())), TrackEventKind::Repeated);
+        assert_eq!(TrackEventKind::of(&Transition::Resumed(roads.clone())), TrackEventKind::Resumed);
+        assert_eq!(TrackEventKind::of(&Transition::Stopped(roads, PlayOutcome::Completed)), TrackEventKind::Stopped);
+
+        let state = AppState::test();
+        let mut events = state.client_sender.subscribe();
+        state.broadcast(TrackInfo::test("Roads", "Portishead", 305.0, 90.0), TrackEventKind::Seeked, None, |_| {});
+        assert_eq!(events.try_recv().unwrap().kind, TrackEventKind::Seeked);
+    }
+
+    #[test]
+    fn event_frames_say_how_the_previous_play_ended() {
+        let state = AppState::test();
+        let mut events = state.client_sender.subscribe();
+        let roads = TrackInfo::test("Roads", "Portishead", 305.0, 0.0);
+        let teardrop = TrackInfo::test("Teardrop", "Massive Attack", 330.0, 0.0);
+
+        state.broadcast(roads, TrackEventKind::TrackChanged, None, |_| {});
+        state.broadcast(teardrop, TrackEventKind::TrackChanged, Some(PlayOutcome::Skipped { position: 42.0 }), |_| {});
+        assert!(frame_json(&events.try_recv().unwrap().frame().unwrap()).get("previous_play").is_none());
+        let json = frame_json(&events.try_recv().unwrap().frame().unwrap());
+        assert_eq!(json["previous_play"], serde_json::json!({ "outcome": "skipped", "position": 42.0 }));
+    }
+
+    #[test]
+    fn new_clients_start_from_the_snapshot() {
+        let state = AppState::test();
+        assert!(state.subscribe_from(None).0.is_empty());
+
+        let track = TrackInfo::test("Roads", "Portishead", 305.0, 42.0);
+        state.broadcast(track.clone(), TrackEventKind::TrackChanged, None, |playback| {
+            playback.track = Some(track.clone());
+            playback.playing = true;
+        });
+        state.update_playback(|playback| {
+            playback.playing = false;
+            playback.state = PlayerState::Paused;
+        });
+
+        let (initial, _frames) = state.subscribe_from(None);
+        assert_eq!(initial.len(), 1);
+        assert_eq!(initial[0].id, 1);
+        let json = frame_json(&initial[0]);
+        assert_eq!(json["track_name"], "Roads");
+        assert_eq!(json["state"], "paused");
+        assert_eq!(json["duration"], -1.0);
+        assert_eq!(json["seq"], state.playback().seq);
+    }
+
+    #[test]
+    fn source_clients_start_from_the_source_status() {
+        let state = AppState::test();
+        let mut status = SourceStatus::new("deck");
+        status.track = Some(TrackInfo::test("Roads", "Portishead", 305.0, 42.0));
+        status.state = PlayerState::Paused;
+        state.sources.lock().unwrap().insert("deck".to_string(), status);
+
+        let (initial, mut frames) = state.subscribe_source("deck");
+        let json = frame_json(&initial.unwrap());
+        assert_eq!(json["track_name"], "Roads");
+        assert_eq!(json["state"], "paused");
+        assert_eq!(json["duration"], -1.0);
+
+        state.publish_source("deck", &TrackInfo::test("Glory Box", "Portishead", 305.0, 0.0));
+        state.publish_source("other", &TrackInfo::test("Teardrop", "Massive Attack", 330.0, 0.0));
+        assert_eq!(frame_json(&frames.try_recv().unwrap())["track_name"], "Glory Box");
+        assert!(frames.try_recv().is_err());
+    }
+
+    #[test]
+    fn resuming_clients_get_the_missed_events() {
+        let state = AppState::test();
+        for name in ["Roads", "Glory Box", "Sour Times"] {
+            state.broadcast(TrackInfo::test(name, "Portishead", 305.0, 0.0), TrackEventKind::TrackChanged, None, |_| {});
+        }
+        let (backlog, _frames) = state.subscribe_from(Some(1));
+        let names: Vec<_> = backlog.iter().map(|frame| frame_json(frame)["track_name"].as_str().unwrap().to_string()).collect();
+        assert_eq!(names, ["Glory Box", "Sour Times"]);
+    }
+}
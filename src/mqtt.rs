@@ -0,0 +1,288 @@
+use std::{sync::{atomic::Ordering, Arc}, time::Duration};
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{config::{MqttConfig, PrivacySink}, models::{AppState, PlayerCommand, PlayerState, TrackEvent}};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Home Assistant has no MQTT `media_player` platform, so playback is exposed
+/// as sensors plus buttons for the control commands.
+const DISCOVERY_SENSORS: [(&str, &str, &str); 4] = [
+    ("track", "Track", "{{ value_json.track_name }}"),
+    ("artist", "Artist", "{{ value_json.artist_name }}"),
+    ("album", "Album", "{{ value_json.album }}"),
+    ("state", "State", "{{ value_json.state }}"),
+];
+
+const DISCOVERY_BUTTONS: [(&str, &str, &str); 3] = [
+    ("play_pause", "Play/Pause", "play_pause"),
+    ("next", "Next Track", "next"),
+    ("previous", "Previous Track", "previous"),
+];
+
+struct Topics {
+    state: String,
+    events: String,
+    availability: String,
+    command: String,
+}
+
+impl Topics {
+    fn new(base_topic: &str) -> Self {
+        let base_topic = base_topic.trim_end_matches('/');
+        Topics {
+            state: format!("{}/state", base_topic),
+            events: format!("{}/events", base_topic),
+            availability: format!("{}/availability", base_topic),
+            command: format!("{}/command", base_topic),
+        }
+    }
+}
+
+pub fn mqtt_task(state: Arc<AppState>, config: MqttConfig) {
+    info!("Starting MQTT task for {}:{}", config.host, config.port);
+
+    let topics = Arc::new(Topics::new(&config.base_topic));
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(&topics.availability, "offline", QoS::AtLeastOnce, true));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 32);
+    let config = Arc::new(config);
+
+    let state_clone = state.clone();
+    let client_clone = client.clone();
+    let topics_clone = topics.clone();
+    let config_clone = config.clone();
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    state_clone.metrics.set_integration_connected("mqtt", true);
+                    tokio::spawn(announce(state_clone.clone(), client_clone.clone(), topics_clone.clone(), config_clone.clone()));
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) if config_clone.commands && publish.topic == topics_clone.command => {
+                    let payload = String::from_utf8_lossy(&publish.payload);
+                    match PlayerCommand::parse(&payload) {
+                        Some(command) => {
+                            info!("Received MQTT command {:?}", command);
+                            if !state_clone.send_player_command(command) {
+                                warn!("No source available to handle MQTT command {:?}", command);
+                            }
+                        }
+                        None => warn!("Ignoring unknown MQTT command: {}", payload),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection error: {}", e);
+                    state_clone.metrics.set_integration_connected("mqtt", false);
+                    state_clone.metrics.record_integration_error("mqtt", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut receiver = state.client_sender.subscribe();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("MQTT task lagged behind, dropped {} messages", skipped);
+                    state.metrics.broadcast_lag_drops.fetch_add(skipped, Ordering::Relaxed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Some(event) = state.privacy.filter_event(PrivacySink::Mqtt, &event) else {
+                // Clear the retained state rather than leave the last shown track playing.
+                if let Err(e) = client.publish(&topics.state, QoS::AtLeastOnce, true, hidden_state_payload().to_string()).await {
+                    warn!("Failed to publish MQTT state: {}", e);
+                    state.metrics.record_integration_error("mqtt", e);
+                }
+                continue;
+            };
+
+            let event_payload = serde_json::json!({
+                "event": event.kind,
+                "id": event.id,
+                "seq": event.seq,
+                "track": event.track,
+                "previous_play": event.ended,
+            });
+
+            if let Err(e) = client.publish(&topics.state, QoS::AtLeastOnce, true, state_payload(&event).to_string()).await {
+                warn!("Failed to publish MQTT state: {}", e);
+                state.metrics.record_integration_error("mqtt", e);
+            }
+            if let Err(e) = client.publish(&topics.events, QoS::AtLeastOnce, false, event_payload.to_string()).await {
+                warn!("Failed to publish MQTT event: {}", e);
+                state.metrics.record_integration_error("mqtt", e);
+            }
+        }
+    });
+}
+
+fn state_payload(event: &TrackEvent) -> serde_json::Value {
+    let mut payload = serde_json::to_value(&event.track).unwrap_or_else(|_| serde_json::json!({}));
+    let player_state = event.track.state.unwrap_or(if event.track.is_paused() { PlayerState::Paused } else { PlayerState::Playing });
+    payload["state"] = serde_json::json!(player_state);
+    payload
+}
+
+/// The retained state while a privacy rule hides the track: no track, as if
+/// the player had stopped.
+fn hidden_state_payload() -> serde_json::Value {
+    serde_json::json!({ "track_name": "", "artist_name": "", "album": "", "state": PlayerState::Stopped })
+}
+
+/// Publishes availability, discovery configs and the current state after
+/// every (re)connect, and subscribes to the command topic if enabled.
+async fn announce(state: Arc<AppState>, client: AsyncClient, topics: Arc<Topics>, config: Arc<MqttConfig>) {
+    let mut publishes: Vec<(String, String)> = vec![(topics.availability.clone(), "online".to_string())];
+
+    if config.discovery {
+        publishes.extend(discovery_configs(&topics, &config));
+    }
+
+    // Like live updates, clear the retained state while its track is hidden.
+    let latest = state.event_history.lock().unwrap().back().cloned();
+    if let Some(latest) = latest {
+        let payload = match state.privacy.filter_event(PrivacySink::Mqtt, &latest) {
+            Some(event) => state_payload(&event),
+            None => hidden_state_payload(),
+        };
+        publishes.push((topics.state.clone(), payload.to_string()));
+    }
+
+    for (topic, payload) in publishes {
+        if let Err(e) = client.publish(&topic, QoS::AtLeastOnce, true, payload).await {
+            warn!("Failed to publish MQTT message to {}: {}", topic, e);
+            state.metrics.record_integration_error("mqtt", e);
+        }
+    }
+
+    if config.commands {
+        if let Err(e) = client.subscribe(&topics.command, QoS::AtLeastOnce).await {
+            warn!("Failed to subscribe to MQTT command topic: {}", e);
+            state.metrics.record_integration_error("mqtt", e);
+        }
+    }
+}
+
+fn discovery_configs(topics: &Topics, config: &MqttConfig) -> Vec<(String, String)> {
+    let node_id = config.base_topic.trim_matches('/').replace(['/', ' '], "_");
+    let device = serde_json::json!({
+        "identifiers": [node_id],
+        "name": "Rusty Tapes",
+        "manufacturer": "aspicho",
+        "model": "Rusty-Tapes",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    let mut configs: Vec<(String, String)> = DISCOVERY_SENSORS.iter().map(|(object_id, name, value_template)| {
+        let payload = serde_json::json!({
+            "name": name,
+            "unique_id": format!("{}_{}", node_id, object_id),
+            "state_topic": topics.state,
+            "value_template": value_template,
+            "json_attributes_topic": topics.state,
+            "availability_topic": topics.availability,
+            "icon": "mdi:music",
+            "device": device,
+        });
+        (format!("{}/sensor/{}/{}/config", config.discovery_prefix, node_id, object_id), payload.to_string())
+    }).collect();
+
+    if config.commands {
+        configs.extend(DISCOVERY_BUTTONS.iter().map(|(object_id, name, command)| {
+            let payload = serde_json::json!({
+                "name": name,
+                "unique_id": format!("{}_{}", node_id, object_id),
+                "command_topic": topics.command,
+                "payload_press": command,
+                "availability_topic": topics.availability,
+                "device": device,
+            });
+            (format!("{}/button/{}/{}/config", config.discovery_prefix, node_id, object_id), payload.to_string())
+        }));
+    }
+
+    configs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{TrackEventKind, TrackInfo};
+
+    fn event(track: TrackInfo) -> TrackEvent {
+        TrackEvent { id: 1, seq: 1, track, kind: TrackEventKind::TrackChanged, privacy_matches: Arc::from([]), api_json: None, ended: None }
+    }
+
+    fn mqtt_config(extra: &str) -> MqttConfig {
+        toml::from_str(&format!("host = \"localhost\"\nbase_topic = \"home/rusty tapes/\"\n{}", extra)).expect("valid MQTT config")
+    }
+
+    #[test]
+    fn state_payload_reports_the_player_state() {
+        let playing = state_payload(&event(TrackInfo::test("Roads", "Portishead", 305.0, 10.0)));
+        assert_eq!(playing["state"], "playing");
+        assert_eq!(playing["track_name"], "Roads");
+
+        let paused = state_payload(&event(TrackInfo { duration: -1.0, ..TrackInfo::test("Roads", "Portishead", 305.0, 10.0) }));
+        assert_eq!(paused["state"], "paused");
+
+        let stopped = TrackInfo { duration: -1.0, state: Some(PlayerState::NotRunning), ..TrackInfo::test("Roads", "Portishead", 305.0, 10.0) };
+        assert_eq!(state_payload(&event(stopped))["state"], "not_running");
+    }
+
+    #[test]
+    fn hidden_tracks_clear_the_retained_state() {
+        let payload = hidden_state_payload();
+        assert_eq!(payload["state"], "stopped");
+        assert_eq!(payload["track_name"], "");
+        assert_eq!(payload["artist_name"], "");
+    }
+
+    #[test]
+    fn topics_trim_the_trailing_slash() {
+        let topics = Topics::new("home/rusty tapes/");
+        assert_eq!(topics.state, "home/rusty tapes/state");
+        assert_eq!(topics.command, "home/rusty tapes/command");
+    }
+
+    #[test]
+    fn discovery_announces_sensors_and_only_enabled_buttons() {
+        let config = mqtt_config("");
+        let topics = Topics::new(&config.base_topic);
+        let configs = discovery_configs(&topics, &config);
+        assert_eq!(configs.len(), DISCOVERY_SENSORS.len());
+
+        let (topic, payload) = &configs[0];
+        assert_eq!(topic, "homeassistant/sensor/home_rusty_tapes/track/config");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["unique_id"], "home_rusty_tapes_track");
+        assert_eq!(payload["device"]["identifiers"][0], "home_rusty_tapes");
+        assert_eq!(payload["state_topic"], topics.state);
+        assert_eq!(payload["availability_topic"], topics.availability);
+
+        let config = mqtt_config("commands = true");
+        let configs = discovery_configs(&topics, &config);
+        assert_eq!(configs.len(), DISCOVERY_SENSORS.len() + DISCOVERY_BUTTONS.len());
+        let (topic, payload) = configs.last().unwrap();
+        assert_eq!(topic, "homeassistant/button/home_rusty_tapes/previous/config");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["command_topic"], topics.command);
+        assert_eq!(payload["payload_press"], "previous");
+    }
+}
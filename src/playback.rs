@@ -0,0 +1,405 @@
+use std::time::{Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::models::TrackInfo;
+
+/// How far the reported progress may drift from the expected position
+/// before it counts as a seek.
+const SEEK_TOLERANCE_SECS: f64 = 3.0;
+/// How close to the end and back to the start a track must jump to count
+/// as a repeat rather than a seek.
+const REPEAT_WINDOW_SECS: f64 = 5.0;
+/// How close to the end a track must get to count as played in full.
+const COMPLETION_WINDOW_SECS: f64 = 10.0;
+
+/// What the selected source reported at a point in time.
+#[derive(Clone, Debug)]
+pub struct Observation {
+    /// When the source made the report, not when it was observed, so that
+    /// re-observing an unchanged report never looks like a seek
+    pub at: Instant,
+    pub playing: bool,
+    pub track: Option<TrackInfo>,
+}
+
+/// A change in playback worth telling clients about.
+#[derive(Clone, Debug)]
+pub enum Transition {
+    /// A new track started playing after nothing was playing
+    Started(TrackInfo),
+    /// A different track replaced the current one; carries how that ended
+    Changed(TrackInfo, PlayOutcome),
+    Paused(TrackInfo),
+    /// The last track continued, after a pause or a stop
+    Resumed(TrackInfo),
+    Seeked(TrackInfo),
+    /// The current track started over from the beginning
+    Repeated(TrackInfo),
+    /// No track is available any more; carries the last one and how it ended
+    Stopped(TrackInfo, PlayOutcome),
+}
+
+/// How a play ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum PlayOutcome {
+    /// The track played to the end, or nearly
+    Completed,
+    /// Another track from the same source replaced it at `position` seconds
+    Skipped { position: f64 },
+    /// Playback stopped or moved to another source at `position` seconds, or
+    /// the track's length is unknown
+    Interrupted { position: f64 },
+}
+
+impl PlayOutcome {
+    /// Classifies how `track` ended, `elapsed` seconds after it was last
+    /// reported playing. `replaced_by` is the track that took over, if any.
+    fn classify(track: &TrackInfo, elapsed: f64, replaced_by: Option<&TrackInfo>) -> Self {
+        let duration = track.duration as f64;
+        let position = match duration > 0.0 {
+            true => (track.progress + elapsed).min(duration),
+            false => track.progress + elapsed,
+        };
+        if duration > 0.0 && position >= duration - COMPLETION_WINDOW_SECS {
+            PlayOutcome::Completed
+        } else if duration > 0.0 && replaced_by.is_some_and(|next| next.source == track.source) {
+            PlayOutcome::Skipped { position }
+        } else {
+            PlayOutcome::Interrupted { position }
+        }
+    }
+}
+
+impl Transition {
+    /// Whether this starts a new play of a track, for scrobbling and stats.
+    pub fn is_new_play(&self) -> bool {
+        matches!(self, Transition::Started(_) | Transition::Changed(..) | Transition::Repeated(_))
+    }
+
+    /// How the previous play ended, for transitions that end one.
+    pub fn ended(&self) -> Option<PlayOutcome> {
+        match self {
+            Transition::Changed(_, ended) | Transition::Stopped(_, ended) => Some(*ended),
+            Transition::Repeated(_) => Some(PlayOutcome::Completed),
+            _ => None,
+        }
+    }
+}
+
+/// Identifies a track as reported by its source, before any privacy rule.
+pub fn identifier(track: &TrackInfo) -> String {
+    format!("{}|{}|{}", track.source, track.track_name, track.artist_name)
+}
+
+/// What the play history and stream sessions keep for a play.
+pub trait PlayEntry {
+    fn add_played_secs(&mut self, secs: f64);
+}
+
+/// The play of a track in progress, timed across pauses.
+pub struct OpenPlay<T> {
+    identifier: String,
+    /// `None` if a privacy rule keeps the track out of the recorder
+    entry: Option<T>,
+    playing_since: Option<SystemTime>,
+}
+
+impl<T: PlayEntry> OpenPlay<T> {
+    fn pause(&mut self, at: SystemTime) {
+        if let (Some(entry), Some(since)) = (&mut self.entry, self.playing_since.take()) {
+            entry.add_played_secs(at.duration_since(since).unwrap_or_default().as_secs_f64());
+        }
+    }
+
+    /// Ends the play at `at`, returning its entry.
+    pub fn finish(mut self, at: SystemTime) -> Option<T> {
+        self.pause(at);
+        self.entry
+    }
+
+    /// The entry as of `at`, counting the time played so far.
+    pub fn snapshot(&self, at: SystemTime) -> Option<T> where T: Clone {
+        let mut entry = self.entry.clone()?;
+        if let Some(since) = self.playing_since {
+            entry.add_played_secs(at.duration_since(since).unwrap_or_default().as_secs_f64());
+        }
+        Some(entry)
+    }
+}
+
+/// Applies a playback transition at `at` to the play in progress, starting a
+/// new one with the entry from `start`. Returns the entry of a play the
+/// transition ended, with how it ended if that is known.
+pub fn record_transition<T: PlayEntry>(
+    current: &mut Option<OpenPlay<T>>,
+    transition: &Transition,
+    at: SystemTime,
+    start: impl FnOnce(&TrackInfo) -> Option<T>,
+) -> Option<(T, Option<PlayOutcome>)> {
+    let open = |track: &TrackInfo| OpenPlay { identifier: identifier(track), entry: start(track), playing_since: Some(at) };
+    let finish = |current: &mut Option<OpenPlay<T>>, ended: Option<PlayOutcome>| {
+        current.take().and_then(|open| open.finish(at)).map(|entry| (entry, ended))
+    };
+
+    match transition {
+        Transition::Started(track) | Transition::Changed(track, _) | Transition::Repeated(track) => {
+            let finished = finish(current, transition.ended());
+            *current = Some(open(track));
+            finished
+        }
+        Transition::Resumed(track) => match current.as_mut() {
+            Some(play) if play.identifier == identifier(track) => {
+                play.playing_since.get_or_insert(at);
+                None
+            }
+            _ => {
+                let finished = finish(current, None);
+                *current = Some(open(track));
+                finished
+            }
+        },
+        Transition::Paused(_) => {
+            if let Some(play) = current.as_mut() {
+                play.pause(at);
+            }
+            None
+        }
+        Transition::Stopped(..) => finish(current, transition.ended()),
+        Transition::Seeked(_) => None,
+    }
+}
+
+#[derive(Clone, Debug)]
+enum PlaybackState {
+    Idle,
+    Playing { track: TrackInfo, at: Instant },
+    Paused { track: TrackInfo },
+}
+
+/// A pure state machine turning observations into [`Transition`]s. It does no
+/// I/O and reads no clocks, so it can be driven with synthetic timestamps.
+#[derive(Debug)]
+pub struct PlaybackTracker {
+    state: PlaybackState,
+    /// The last track played, kept across stops so that continuing it
+    /// counts as resuming rather than a new play
+    last_identifier: Option<String>,
+}
+
+impl Default for PlaybackTracker {
+    fn default() -> Self {
+        PlaybackTracker { state: PlaybackState::Idle, last_identifier: None }
+    }
+}
+
+impl PlaybackTracker {
+    pub fn observe(&mut self, observation: Observation) -> Option<Transition> {
+        let Some(track) = observation.track else {
+            let previous = std::mem::replace(&mut self.state, PlaybackState::Idle);
+            return match previous {
+                PlaybackState::Idle => None,
+                PlaybackState::Playing { track, at } => {
+                    let elapsed = observation.at.saturating_duration_since(at).as_secs_f64();
+                    let ended = PlayOutcome::classify(&track, elapsed, None);
+                    Some(Transition::Stopped(track, ended))
+                }
+                PlaybackState::Paused { track } => {
+                    let ended = PlayOutcome::classify(&track, 0.0, None);
+                    Some(Transition::Stopped(track, ended))
+                }
+            };
+        };
+
+        if !observation.playing {
+            let previous = std::mem::replace(&mut self.state, PlaybackState::Paused { track: track.clone() });
+            return match previous {
+                PlaybackState::Playing { .. } => Some(Transition::Paused(track)),
+                // Nothing was showing, so a paused track is not news; keep idle.
+                PlaybackState::Idle => {
+                    self.state = PlaybackState::Idle;
+                    None
+                }
+                PlaybackState::Paused { .. } => None,
+            };
+        }
+
+        let track_identifier = identifier(&track);
+        let is_same_track = self.last_identifier.as_ref() == Some(&track_identifier);
+        let previous = std::mem::replace(&mut self.state, PlaybackState::Playing { track: track.clone(), at: observation.at });
+        self.last_identifier = Some(track_identifier);
+
+        match previous {
+            _ if !is_same_track => match previous {
+                PlaybackState::Idle => Some(Transition::Started(track)),
+                PlaybackState::Playing { track: previous, at } => {
+                    let elapsed = observation.at.saturating_duration_since(at).as_secs_f64();
+                    let ended = PlayOutcome::classify(&previous, elapsed, Some(&track));
+                    Some(Transition::Changed(track, ended))
+                }
+                PlaybackState::Paused { track: previous } => {
+                    let ended = PlayOutcome::classify(&previous, 0.0, Some(&track));
+                    Some(Transition::Changed(track, ended))
+                }
+            },
+            PlaybackState::Idle | PlaybackState::Paused { .. } => Some(Transition::Resumed(track)),
+            PlaybackState::Playing { track: previous, at } => {
+                let elapsed = observation.at.saturating_duration_since(at).as_secs_f64();
+                detect_jump(&previous, &track, elapsed)
+            }
+        }
+    }
+}
+
+/// Compares the reported progress with where playback should have got to.
+fn detect_jump(previous: &TrackInfo, current: &TrackInfo, elapsed: f64) -> Option<Transition> {
+    // Sources without a clock (radio, some pushed players) report a fixed
+    // progress; that is not a seek.
+    if current.duration <= 0.0 || current.progress == previous.progress {
+        return None;
+    }
+
+    let expected = previous.progress + elapsed;
+    if (current.progress - expected).abs() <= SEEK_TOLERANCE_SECS {
+        return None;
+    }
+
+    let near_end = previous.progress >= current.duration as f64 - REPEAT_WINDOW_SECS - elapsed;
+    if near_end && current.progress <= REPEAT_WINDOW_SECS {
+        Some(Transition::Repeated(current.clone()))
+    } else {
+        Some(Transition::Seeked(current.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Feeds observations at offsets in seconds from a fixed start.
+    struct Driver {
+        tracker: PlaybackTracker,
+        start: Instant,
+    }
+
+    impl Driver {
+        fn new() -> Self {
+            Driver { tracker: PlaybackTracker::default(), start: Instant::now() }
+        }
+
+        fn observe(&mut self, secs: f64, playing: bool, track: Option<TrackInfo>) -> Option<Transition> {
+            let at = self.start + Duration::from_secs_f64(secs);
+            self.tracker.observe(Observation { at, playing, track })
+        }
+
+        fn play(&mut self, secs: f64, track_name: &str, progress: f64) -> Option<Transition> {
+            self.observe(secs, true, Some(TrackInfo::test(track_name, "Portishead", 300.0, progress)))
+        }
+    }
+
+    #[test]
+    fn classifies_how_plays_ended() {
+        let track = TrackInfo::test("Roads", "Portishead", 300.0, 200.0);
+        let next = TrackInfo::test("Glory Box", "Portishead", 300.0, 0.0);
+        let elsewhere = TrackInfo { source: "other".to_string(), ..next.clone() };
+
+        // Within the completion window of the end counts as played in full.
+        assert_eq!(PlayOutcome::classify(&track, 90.0, Some(&next)), PlayOutcome::Completed);
+        assert_eq!(PlayOutcome::classify(&track, 150.0, None), PlayOutcome::Completed);
+        assert_eq!(PlayOutcome::classify(&track, 20.0, Some(&next)), PlayOutcome::Skipped { position: 220.0 });
+        assert_eq!(PlayOutcome::classify(&track, 20.0, Some(&elsewhere)), PlayOutcome::Interrupted { position: 220.0 });
+        assert_eq!(PlayOutcome::classify(&track, 20.0, None), PlayOutcome::Interrupted { position: 220.0 });
+
+        // A track of unknown length can neither complete nor be skipped.
+        let stream = TrackInfo::test("Live", "Radio", 0.0, 3000.0);
+        assert_eq!(PlayOutcome::classify(&stream, 60.0, Some(&next)), PlayOutcome::Interrupted { position: 3060.0 });
+    }
+
+    #[test]
+    fn starts_changes_and_stops() {
+        let mut driver = Driver::new();
+        assert!(matches!(driver.play(0.0, "Roads", 0.0), Some(Transition::Started(track)) if track.track_name == "Roads"));
+        assert!(driver.play(5.0, "Roads", 5.0).is_none());
+        assert!(matches!(driver.play(10.0, "Glory Box", 0.0), Some(Transition::Changed(track, PlayOutcome::Skipped { .. })) if track.track_name == "Glory Box"));
+        assert!(matches!(driver.observe(20.0, false, None), Some(Transition::Stopped(track, PlayOutcome::Interrupted { .. })) if track.track_name == "Glory Box"));
+        assert!(driver.observe(25.0, false, None).is_none());
+    }
+
+    #[test]
+    fn pauses_and_resumes() {
+        let mut driver = Driver::new();
+        driver.play(0.0, "Roads", 0.0);
+        let paused = Some(TrackInfo::test("Roads", "Portishead", 300.0, 10.0));
+        assert!(matches!(driver.observe(10.0, false, paused.clone()), Some(Transition::Paused(_))));
+        assert!(driver.observe(15.0, false, paused).is_none());
+        assert!(matches!(driver.play(60.0, "Roads", 10.0), Some(Transition::Resumed(_))));
+    }
+
+    #[test]
+    fn resumes_the_last_track_after_a_stop() {
+        let mut driver = Driver::new();
+        driver.play(0.0, "Roads", 0.0);
+        driver.observe(10.0, false, None);
+        assert!(matches!(driver.play(20.0, "Roads", 10.0), Some(Transition::Resumed(_))));
+    }
+
+    #[test]
+    fn paused_tracks_are_not_news_while_idle() {
+        let mut driver = Driver::new();
+        assert!(driver.observe(0.0, false, Some(TrackInfo::test("Roads", "Portishead", 300.0, 10.0))).is_none());
+        assert!(matches!(driver.play(5.0, "Roads", 10.0), Some(Transition::Started(_))));
+    }
+
+    #[test]
+    fn seeks_only_outside_the_tolerance() {
+        let mut driver = Driver::new();
+        driver.play(0.0, "Roads", 0.0);
+        assert!(driver.play(10.0, "Roads", 10.0 + SEEK_TOLERANCE_SECS - 0.5).is_none());
+        assert!(matches!(driver.play(20.0, "Roads", 120.0), Some(Transition::Seeked(track)) if track.progress == 120.0));
+        assert!(matches!(driver.play(30.0, "Roads", 60.0), Some(Transition::Seeked(_))));
+    }
+
+    #[test]
+    fn repeats_when_jumping_from_the_end_to_the_start() {
+        let mut driver = Driver::new();
+        driver.play(0.0, "Roads", 290.0);
+        let repeated = driver.play(10.0, "Roads", 1.0);
+        assert!(matches!(repeated, Some(Transition::Repeated(_))));
+        assert_eq!(repeated.unwrap().ended(), Some(PlayOutcome::Completed));
+    }
+
+    #[test]
+    fn fixed_progress_is_not_a_seek() {
+        let radio = TrackInfo::test("Radio/Mix", "Unknown", 0.0, 0.0);
+        assert!(detect_jump(&radio, &radio, 60.0).is_none());
+        let frozen = TrackInfo::test("Roads", "Portishead", 300.0, 42.0);
+        assert!(detect_jump(&frozen, &frozen, 60.0).is_none());
+    }
+
+    proptest! {
+        /// Progress that keeps up with the clock, give or take a second of
+        /// reporting jitter, never looks like a seek.
+        #[test]
+        fn steady_progress_never_seeks(
+            start in 0.0..100.0f64,
+            steps in prop::collection::vec((0.5..10.0f64, -1.0..1.0f64), 1..50),
+        ) {
+            let mut driver = Driver::new();
+            let duration = start + steps.iter().map(|(elapsed, _)| elapsed).sum::<f64>() + 60.0;
+            let track = |progress: f64| Some(TrackInfo::test("Roads", "Portishead", duration as f32, progress.max(0.0)));
+            driver.observe(0.0, true, track(start));
+
+            let mut secs = 0.0;
+            for (elapsed, jitter) in steps {
+                secs += elapsed;
+                let transition = driver.observe(secs, true, track(start + secs + jitter));
+                prop_assert!(transition.is_none(), "unexpected {:?} at {}s", transition, secs);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{config::{PrivacyAction, PrivacyField, PrivacyRuleConfig, PrivacySink}, models::{TrackEvent, TrackInfo}};
+
+struct PrivacyRule {
+    title: Option<Regex>,
+    artist: Option<Regex>,
+    album: Option<Regex>,
+    genre: Option<Regex>,
+    action: PrivacyAction,
+    redact: Vec<PrivacyField>,
+    placeholder: String,
+    sinks: Vec<PrivacySink>,
+}
+
+impl PrivacyRule {
+    fn matches(&self, track: &TrackInfo) -> bool {
+        let field_matches = |pattern: &Option<Regex>, value: &str| pattern.as_ref().is_none_or(|pattern| pattern.is_match(value));
+        field_matches(&self.title, &track.track_name)
+            && field_matches(&self.artist, &track.artist_name)
+            && field_matches(&self.album, &track.album)
+            && field_matches(&self.genre, &track.genre)
+    }
+
+    fn applies_to(&self, sink: PrivacySink) -> bool {
+        self.sinks.is_empty() || self.sinks.contains(&sink)
+    }
+
+    fn apply(&self, track: &TrackInfo) -> Option<TrackInfo> {
+        let mut track = track.clone();
+        match self.action {
+            PrivacyAction::Hide => return None,
+            PrivacyAction::Redact => {
+                for field in &self.redact {
+                    match field {
+                        PrivacyField::Title => track.track_name = "Unknown".to_string(),
+                        PrivacyField::Artist => track.artist_name = "Unknown".to_string(),
+                        PrivacyField::Album => track.album = "Unknown".to_string(),
+                        PrivacyField::Genre => track.genre = "Unknown".to_string(),
+                        PrivacyField::Artwork => track.artwork_url = Some(String::new()),
+                    }
+                }
+            }
+            PrivacyAction::Placeholder => {
+                track = TrackInfo {
+                    track_name: self.placeholder.clone(),
+                    artist_name: "Unknown".to_string(),
+                    genre: "Unknown".to_string(),
+                    album: "Unknown".to_string(),
+                    favourited: false,
+                    played_count: 0,
+                    artwork_url: Some(String::new()),
+                    ..track
+                };
+            }
+        }
+        Some(track)
+    }
+}
+
+/// The compiled `[[privacy]]` rules. Matching runs once per track event;
+/// each sink then applies the first matching rule that covers it.
+pub struct PrivacyFilter {
+    rules: Vec<PrivacyRule>,
+}
+
+impl PrivacyFilter {
+    pub fn new(configs: &[PrivacyRuleConfig]) -> Result<Self, String> {
+        let rules = configs.iter().enumerate().map(|(index, config)| {
+            let rule_name = config.name.clone().unwrap_or_else(|| format!("#{}", index + 1));
+            let compile = |pattern: &Option<String>| pattern.as_deref()
+                .map(|pattern| RegexBuilder::new(pattern).case_insensitive(true).build()
+                    .map_err(|e| format!("Invalid pattern in privacy rule {}: {}", rule_name, e)))
+                .transpose();
+
+            let rule = PrivacyRule {
+                title: compile(&config.title)?,
+                artist: compile(&config.artist)?,
+                album: compile(&config.album)?,
+                genre: compile(&config.genre)?,
+                action: config.action,
+                redact: config.redact.clone(),
+                placeholder: config.placeholder.clone(),
+                sinks: config.sinks.clone(),
+            };
+            if rule.title.is_none() && rule.artist.is_none() && rule.album.is_none() && rule.genre.is_none() {
+                return Err(format!("Privacy rule {} must match on at least one of title, artist, album or genre", rule_name));
+            }
+            if rule.action == PrivacyAction::Redact && rule.redact.is_empty() {
+                return Err(format!("Privacy rule {} uses the redact action but lists no fields to redact", rule_name));
+            }
+            Ok(rule)
+        }).collect::<Result<_, String>>()?;
+        Ok(PrivacyFilter { rules })
+    }
+
+    /// The indices of the rules matching `track`.
+    pub fn matching(&self, track: &TrackInfo) -> Arc<[usize]> {
+        self.rules.iter().enumerate()
+            .filter(|(_, rule)| rule.matches(track))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn apply(&self, sink: PrivacySink, track: &TrackInfo, matched: &[usize]) -> Option<Option<TrackInfo>> {
+        matched.iter()
+            .map(|&index| &self.rules[index])
+            .find(|rule| rule.applies_to(sink))
+            .map(|rule| rule.apply(track))
+    }
+
+    /// The event as `sink` may see it, or `None` if it must be hidden.
+    pub fn filter_event(&self, sink: PrivacySink, event: &TrackEvent) -> Option<TrackEvent> {
+        match self.apply(sink, &event.track, &event.privacy_matches) {
+            None => Some(event.clone()),
+            Some(track) => track.map(|track| TrackEvent { track, ..event.clone() }),
+        }
+    }
+
+    /// Like [`PrivacyFilter::filter_event`], for tracks outside the event stream.
+    pub fn filter_track(&self, sink: PrivacySink, track: &TrackInfo) -> Option<TrackInfo> {
+        match self.apply(sink, track, &self.matching(track)) {
+            None => Some(track.clone()),
+            Some(track) => track,
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::{fmt::Write, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use axum::{body::Body, extract::{Query, State}, http::{header, StatusCode}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config::SessionConfig, history::UNKNOWN, models::{AppState, Args, SessionAction, TrackInfo}, playback::{self, OpenPlay, PlayEntry, Transition}, utils};
+
+/// A stream session: the tracks played between its start and stop.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    /// Unix time in milliseconds; `None` while recording
+    pub ended_at: Option<u64>,
+    pub tracks: Vec<SessionTrack>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionTrack {
+    /// When the track started, in seconds from the start of the session
+    pub offset_secs: f64,
+    pub track_name: String,
+    pub artist_name: String,
+    pub album: String,
+    /// Time spent playing the track, excluding pauses
+    pub played_secs: f64,
+}
+
+impl PlayEntry for SessionTrack {
+    fn add_played_secs(&mut self, secs: f64) {
+        self.played_secs += secs;
+    }
+}
+
+/// The session being recorded and the track in it that is playing, which
+/// joins the session's tracks once it ends.
+struct Recording {
+    session: Session,
+    started: SystemTime,
+    current: Option<OpenPlay<SessionTrack>>,
+}
+
+impl Recording {
+    /// The session as of `at`, counting the current track's time so far.
+    fn snapshot(&self, at: SystemTime) -> Session {
+        let mut session = self.session.clone();
+        session.tracks.extend(self.current.as_ref().and_then(|current| current.snapshot(at)));
+        session
+    }
+}
+
+/// Records stream sessions from playback transitions.
+pub struct SessionRecorder {
+    config: SessionConfig,
+    recording: Mutex<Option<Recording>>,
+    /// The last session stopped since the server started
+    last: Mutex<Option<Session>>,
+}
+
+impl SessionRecorder {
+    pub fn new(config: &SessionConfig) -> Self {
+        SessionRecorder { config: config.clone(), recording: Mutex::new(None), last: Mutex::new(None) }
+    }
+
+    pub fn start(&self, name: Option<String>, at: SystemTime) -> Result<(), String> {
+        let mut recording = self.recording.lock().unwrap();
+        if let Some(recording) = recording.as_ref() {
+            return Err(format!("Session '{}' is already being recorded", recording.session.name));
+        }
+        let name = name.filter(|name| !name.trim().is_empty())
+            .unwrap_or_else(|| format!("Stream {}", utils::rfc3339(at).get(..10).unwrap_or_default()));
+        info!("Started recording session '{}'", name);
+        *recording = Some(Recording {
+            session: Session { name, started_at: utils::unix_millis(at), ended_at: None, tracks: Vec::new() },
+            started: at,
+            current: None,
+        });
+        Ok(())
+    }
+
+    /// Ends the session being recorded and returns it.
+    pub fn stop(&self, at: SystemTime) -> Result<Session, String> {
+        let Some(mut recording) = self.recording.lock().unwrap().take() else {
+            return Err("No session is being recorded".to_string());
+        };
+        recording.session.tracks.extend(recording.current.take().and_then(|current| current.finish(at)));
+        recording.session.ended_at = Some(utils::unix_millis(at));
+        info!("Stopped recording session '{}' with {} tracks", recording.session.name, recording.session.tracks.len());
+        *self.last.lock().unwrap() = Some(recording.session.clone());
+        Ok(recording.session)
+    }
+
+    /// Adds a playback transition at `at` to the session being recorded, if
+    /// any. `visible` applies the privacy rules to a newly started track.
+    pub fn record(&self, transition: &Transition, at: SystemTime, visible: impl Fn(&TrackInfo) -> Option<TrackInfo>) {
+        let mut recording = self.recording.lock().unwrap();
+        let Some(recording) = recording.as_mut() else {
+            return;
+        };
+        let offset_secs = at.duration_since(recording.started).unwrap_or_default().as_secs_f64();
+        let start = |track: &TrackInfo| visible(track).map(|visible| SessionTrack {
+            offset_secs,
+            track_name: visible.track_name,
+            artist_name: visible.artist_name,
+            album: visible.album,
+            played_secs: 0.0,
+        });
+        if let Some((track, _)) = playback::record_transition(&mut recording.current, transition, at, start) {
+            recording.session.tracks.push(track);
+        }
+    }
+
+    /// The session being recorded, or else the last one stopped, and whether
+    /// it is still being recorded.
+    pub fn current(&self, at: SystemTime) -> Option<(Session, bool)> {
+        if let Some(recording) = self.recording.lock().unwrap().as_ref() {
+            return Some((recording.snapshot(at), true));
+        }
+        self.last.lock().unwrap().clone().map(|session| (session, false))
+    }
+
+    /// Saves a stopped session as JSON, returning the file written.
+    pub async fn save(&self, session: &Session) -> Result<PathBuf, String> {
+        let started_at = utils::rfc3339(UNIX_EPOCH + Duration::from_millis(session.started_at));
+        let path = self.config.dir.join(format!("{}.json", started_at.replace(':', "-")));
+        tokio::fs::create_dir_all(&self.config.dir).await
+            .map_err(|e| format!("Failed to create session directory {}: {}", self.config.dir.display(), e))?;
+        let json = serde_json::to_vec_pretty(session).unwrap_or_default();
+        utils::write_atomically(&path, &json).await
+            .map_err(|e| format!("Failed to save session to {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TracklistFormat {
+    /// Chapter lines for a YouTube video description
+    Youtube,
+    /// A numbered tracklist for a Twitch VOD description
+    Twitch,
+    /// A CUE sheet for the stream recording
+    Cue,
+    /// A Markdown table
+    Markdown,
+}
+
+impl TracklistFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            TracklistFormat::Youtube | TracklistFormat::Twitch => "text/plain; charset=utf-8",
+            TracklistFormat::Cue => "application/x-cue; charset=utf-8",
+            TracklistFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            TracklistFormat::Youtube | TracklistFormat::Twitch => "txt",
+            TracklistFormat::Cue => "cue",
+            TracklistFormat::Markdown => "md",
+        }
+    }
+}
+
+/// Writes the session's tracklist, leaving out tracks played for less than
+/// `min_secs`.
+pub fn tracklist(session: &Session, format: TracklistFormat, min_secs: u64) -> String {
+    let tracks: Vec<&SessionTrack> = session.tracks.iter()
+        .filter(|track| track.played_secs >= min_secs as f64)
+        .collect();
+    let with_hours = tracks.last().is_some_and(|track| track.offset_secs >= 3600.0);
+    let mut out = String::new();
+
+    match format {
+        TracklistFormat::Youtube => {
+            // YouTube only turns the list into chapters if it starts at 0:00.
+            if tracks.first().is_some_and(|track| track.offset_secs >= 1.0) {
+                let _ = writeln!(out, "{} Intro", timestamp(0.0, with_hours));
+            }
+            for track in &tracks {
+                let _ = writeln!(out, "{} {}", timestamp(track.offset_secs, with_hours), title(track));
+            }
+        }
+        TracklistFormat::Twitch => {
+            let _ = writeln!(out, "{}\n\nTracklist:", session.name);
+            for (number, track) in tracks.iter().enumerate() {
+                let _ = writeln!(out, "{}. [{}] {}", number + 1, timestamp(track.offset_secs, true), title(track));
+            }
+        }
+        TracklistFormat::Cue => {
+            let quoted = |value: &str| value.replace('"', "'");
+            let _ = writeln!(out, "REM DATE {}", utils::rfc3339(UNIX_EPOCH + Duration::from_millis(session.started_at)).get(..10).unwrap_or_default());
+            let _ = writeln!(out, "TITLE \"{}\"", quoted(&session.name));
+            let _ = writeln!(out, "FILE \"{}.wav\" WAVE", quoted(&session.name));
+            for (number, track) in tracks.iter().enumerate() {
+                let frames = (track.offset_secs * 75.0) as u64;
+                let _ = writeln!(out, "  TRACK {:02} AUDIO", number + 1);
+                let _ = writeln!(out, "    TITLE \"{}\"", quoted(&track.track_name));
+                let _ = writeln!(out, "    PERFORMER \"{}\"", quoted(&track.artist_name));
+                let _ = writeln!(out, "    INDEX 01 {:02}:{:02}:{:02}", frames / 4500, frames / 75 % 60, frames % 75);
+            }
+        }
+        TracklistFormat::Markdown => {
+            let cell = |value: &str| value.replace('|', "\\|");
+            let _ = writeln!(out, "## {}\n", session.name);
+            let _ = writeln!(out, "| # | Time | Track | Artist | Album |");
+            let _ = writeln!(out, "|---|------|-------|--------|-------|");
+            for (number, track) in tracks.iter().enumerate() {
+                let _ = writeln!(out, "| {} | {} | {} | {} | {} |", number + 1, timestamp(track.offset_secs, with_hours),
+                    cell(&track.track_name), cell(&track.artist_name), cell(&track.album));
+            }
+        }
+    }
+    out
+}
+
+fn title(track: &SessionTrack) -> String {
+    match track.artist_name.as_str() {
+        "" | UNKNOWN => track.track_name.clone(),
+        artist => format!("{} - {}", artist, track.track_name),
+    }
+}
+
+/// `M:SS`, or `H:MM:SS` if `with_hours`.
+fn timestamp(secs: f64, with_hours: bool) -> String {
+    let secs = secs as u64;
+    match with_hours {
+        true => format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+        false => format!("{}:{:02}", secs / 60, secs % 60),
+    }
+}
+
+#[derive(Deserialize)]
+struct StartParams {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TracklistParams {
+    format: TracklistFormat,
+    /// Overrides `min_track_secs` from the config
+    min_duration: Option<u64>,
+}
+
+/// `GET /api/session`, `POST /api/session/start`, `POST /api/session/stop`
+/// and `GET /api/session/tracklist`.
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/session", get(get_session))
+        .route("/api/session/start", post(start_session))
+        .route("/api/session/stop", post(stop_session))
+        .route("/api/session/tracklist", get(session_tracklist))
+}
+
+async fn get_session(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    match state.session.current(SystemTime::now()) {
+        Some((session, recording)) => (StatusCode::OK, Json(serde_json::json!({ "recording": recording, "session": session }))),
+        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "No session has been recorded" }))),
+    }
+}
+
+async fn start_session(State(state): State<Arc<AppState>>, Query(params): Query<StartParams>) -> (StatusCode, Json<serde_json::Value>) {
+    let now = SystemTime::now();
+    if let Err(e) = state.start_session(params.name, now) {
+        return (StatusCode::CONFLICT, Json(serde_json::json!({ "error": e })));
+    }
+    let session = state.session.current(now).map(|(session, _)| session);
+    (StatusCode::OK, Json(serde_json::json!({ "recording": true, "session": session })))
+}
+
+async fn stop_session(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let session = match state.session.stop(SystemTime::now()) {
+        Ok(session) => session,
+        Err(e) => return (StatusCode::CONFLICT, Json(serde_json::json!({ "error": e }))),
+    };
+    let file = match state.session.save(&session).await {
+        Ok(path) => Some(path),
+        Err(e) => {
+            warn!("{}", e);
+            None
+        }
+    };
+    (StatusCode::OK, Json(serde_json::json!({ "recording": false, "session": session, "file": file })))
+}
+
+async fn session_tracklist(State(state): State<Arc<AppState>>, Query(params): Query<TracklistParams>) -> Response {
+    let Some((session, _)) = state.session.current(SystemTime::now()) else {
+        return (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "No session has been recorded" }))).into_response();
+    };
+    let min_secs = params.min_duration.unwrap_or(state.session.config.min_track_secs);
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, params.format.content_type())
+        .header(header::CONTENT_DISPOSITION, format!("inline; filename=\"tracklist.{}\"", params.format.extension()))
+        .body(Body::from(tracklist(&session, params.format, min_secs)))
+        .unwrap()
+}
+
+/// Runs a `session` command, against the running server unless a saved
+/// session file is given.
+pub async fn run(action: &SessionAction, args: &Args, config: &SessionConfig) -> Result<(), String> {
+    if let SessionAction::Tracklist { format, min_duration, file: Some(file) } = action {
+        let json = std::fs::read(file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+        let session: Session = serde_json::from_slice(&json).map_err(|e| format!("Invalid session file {}: {}", file.display(), e))?;
+        print!("{}", tracklist(&session, *format, min_duration.unwrap_or(config.min_track_secs)));
+        return Ok(());
+    }
+
+    let host = match args.host.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        host => host,
+    };
+    let base = match host.contains(':') {
+        true => format!("http://[{}]:{}/api/session", host, args.port),
+        false => format!("http://{}:{}/api/session", host, args.port),
+    };
+    let client = reqwest::Client::new();
+    let request = match action {
+        SessionAction::Start { name } => {
+            let query = name.as_deref().map(|name| format!("?name={}", urlencoding::encode(name))).unwrap_or_default();
+            client.post(format!("{}/start{}", base, query))
+        }
+        SessionAction::Stop => client.post(format!("{}/stop", base)),
+        SessionAction::Tracklist { format, min_duration, .. } => {
+            let format = format.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+            let min_duration = min_duration.map(|secs| format!("&min_duration={}", secs)).unwrap_or_default();
+            client.get(format!("{}/tracklist?format={}{}", base, format, min_duration))
+        }
+    };
+    let request = match &args.api_token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    };
+
+    let response = request.send().await.map_err(|e| format!("Failed to reach Rusty-Tapes at {}: {}", base, e))?;
+    let status = response.status();
+    let body = response.text().await.map_err(|e| format!("Failed to read the response: {}", e))?;
+    if !status.is_success() {
+        let error = serde_json::from_str::<serde_json::Value>(&body).ok()
+            .and_then(|value| value.get("error").and_then(|error| error.as_str()).map(str::to_string))
+            .unwrap_or(body);
+        return Err(format!("Session request failed ({}): {}", status, error));
+    }
+
+    if let SessionAction::Tracklist { .. } = action {
+        print!("{}", body);
+        return Ok(());
+    }
+    let response: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+    match action {
+        SessionAction::Start { .. } => info!("Recording session '{}'", response["session"]["name"].as_str().unwrap_or_default()),
+        SessionAction::Tracklist { .. } => {}
+        SessionAction::Stop => {
+            let tracks = response["session"]["tracks"].as_array().map_or(0, Vec::len);
+            match response["file"].as_str() {
+                Some(file) => info!("Stopped session with {} tracks, saved to {}", tracks, file),
+                None => info!("Stopped session with {} tracks", tracks),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback::PlayOutcome;
+
+    fn track(offset_secs: f64, track_name: &str, artist_name: &str, played_secs: f64) -> SessionTrack {
+        SessionTrack { offset_secs, track_name: track_name.to_string(), artist_name: artist_name.to_string(), album: UNKNOWN.to_string(), played_secs }
+    }
+
+    fn session(tracks: Vec<SessionTrack>) -> Session {
+        Session { name: "Late Show".to_string(), started_at: 1_706_724_180_000, ended_at: None, tracks }
+    }
+
+    #[test]
+    fn youtube_chapters_start_at_zero() {
+        let late_start = session(vec![track(30.0, "Roads", "Portishead", 240.0), track(270.0, "Intermission", UNKNOWN, 60.0)]);
+        assert_eq!(tracklist(&late_start, TracklistFormat::Youtube, 30), "0:00 Intro\n0:30 Portishead - Roads\n4:30 Intermission\n");
+
+        let on_time = session(vec![track(0.4, "Roads", "Portishead", 240.0), track(3700.0, "Angel", "Massive Attack", 400.0)]);
+        assert_eq!(tracklist(&on_time, TracklistFormat::Youtube, 30), "0:00:00 Portishead - Roads\n1:01:40 Massive Attack - Angel\n");
+    }
+
+    #[test]
+    fn cue_indexes_count_75_frames_per_second() {
+        let tracks = vec![track(61.5, "Roads", "Portishead", 240.0), track(3725.2, "Say \"Yes\"", "Elliott Smith", 130.0)];
+        let cue = tracklist(&session(tracks), TracklistFormat::Cue, 30);
+        assert!(cue.starts_with("REM DATE 2024-01-31\nTITLE \"Late Show\"\nFILE \"Late Show.wav\" WAVE\n"));
+        assert!(cue.contains("  TRACK 01 AUDIO\n    TITLE \"Roads\"\n    PERFORMER \"Portishead\"\n    INDEX 01 01:01:37\n"));
+        assert!(cue.contains("  TRACK 02 AUDIO\n    TITLE \"Say 'Yes'\"\n    PERFORMER \"Elliott Smith\"\n    INDEX 01 62:05:15\n"));
+    }
+
+    #[test]
+    fn leaves_out_tracks_played_for_less_than_min_secs() {
+        let tracks = vec![track(0.0, "Roads", "Portishead", 240.0), track(240.0, "Sour Times", "Portishead", 29.9), track(270.0, "Glory Box", "Portishead", 30.0)];
+        let session = session(tracks);
+        assert_eq!(tracklist(&session, TracklistFormat::Twitch, 30), "Late Show\n\nTracklist:\n1. [0:00:00] Portishead - Roads\n2. [0:04:30] Portishead - Glory Box\n");
+        assert_eq!(tracklist(&session, TracklistFormat::Twitch, 0).lines().count(), 6);
+    }
+
+    #[test]
+    fn records_playing_time_without_pauses_or_hidden_tracks() {
+        let recorder = SessionRecorder::new(&SessionConfig::default());
+        let start = UNIX_EPOCH + Duration::from_secs(1_706_724_180);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let roads = TrackInfo::test("Roads", "Portishead", 300.0, 0.0);
+        let hidden = TrackInfo::test("Secret", "Portishead", 300.0, 0.0);
+        let visible = |track: &TrackInfo| (track.track_name != "Secret").then(|| track.clone());
+
+        recorder.start(None, start).unwrap();
+        recorder.record(&Transition::Started(roads.clone()), at(10), visible);
+        recorder.record(&Transition::Paused(roads.clone()), at(70), visible);
+        recorder.record(&Transition::Resumed(roads.clone()), at(100), visible);
+        assert_eq!(recorder.current(at(120)).unwrap().0.tracks[0].played_secs, 80.0);
+
+        recorder.record(&Transition::Changed(hidden.clone(), PlayOutcome::Skipped { position: 80.0 }), at(120), visible);
+        recorder.record(&Transition::Stopped(hidden, PlayOutcome::Interrupted { position: 10.0 }), at(130), visible);
+        let session = recorder.stop(at(140)).unwrap();
+        assert_eq!(session.name, "Stream 2024-01-31");
+        let tracks: Vec<(f64, &str, f64)> = session.tracks.iter().map(|track| (track.offset_secs, track.track_name.as_str(), track.played_secs)).collect();
+        assert_eq!(tracks, [(10.0, "Roads", 80.0)]);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use axum::{extract::{ws::{Message, WebSocket}, WebSocketUpgrade}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, routing::{any, post}, Extension, Json, Router};
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{auth, config::IngestConfig, models::{AppState, PlayerCommand, PlayerState, TrackInfo}, sources::SourceReport};
+
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A `TrackInfo`-shaped update pushed by an external player. Only `source`
+/// and, while playing, `track_name` are required.
+#[derive(Debug, serde::Deserialize)]
+pub struct IngestPayload {
+    pub source: String,
+    #[serde(default = "default_playing")]
+    pub playing: bool,
+    /// The full player state, taking precedence over `playing`
+    pub state: Option<PlayerState>,
+    pub track_name: Option<String>,
+    pub artist_name: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub progress: f64,
+    #[serde(default)]
+    pub duration: f32,
+    #[serde(default)]
+    pub favourited: bool,
+    #[serde(default)]
+    pub played_count: i32,
+    pub artwork_url: Option<String>,
+}
+
+fn default_playing() -> bool {
+    true
+}
+
+impl IngestPayload {
+    fn into_report(self) -> Result<SourceReport, String> {
+        let player_state = self.state.unwrap_or(if self.playing { PlayerState::Playing } else { PlayerState::Paused });
+        let track = match self.track_name {
+            Some(track_name) => Some(TrackInfo {
+                track_name,
+                artist_name: self.artist_name.unwrap_or_else(|| "Unknown".to_string()),
+                progress: self.progress,
+                duration: self.duration,
+                genre: self.genre.unwrap_or_else(|| "Unknown".to_string()),
+                favourited: self.favourited,
+                played_count: self.played_count,
+                album: self.album.unwrap_or_else(|| "Unknown".to_string()),
+                source: self.source.clone(),
+                artwork_url: self.artwork_url,
+                timing: None,
+                state: None,
+            }),
+            None if player_state.is_playing() => return Err("track_name is required while playing".to_string()),
+            None => None,
+        };
+        Ok(SourceReport { source: self.source, state: player_state, track })
+    }
+}
+
+struct IngestSource {
+    token: Option<String>,
+    last_update: Mutex<Option<Instant>>,
+}
+
+/// The ingest sources declared with `--source ingest:<name>`.
+pub struct IngestRegistry {
+    state: Arc<AppState>,
+    sources: HashMap<String, IngestSource>,
+    reports: mpsc::Sender<SourceReport>,
+}
+
+impl IngestRegistry {
+    pub fn new(state: Arc<AppState>, reports: mpsc::Sender<SourceReport>) -> Self {
+        IngestRegistry { state, sources: HashMap::new(), reports }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    pub fn add_source(&mut self, name: &str, config: &IngestConfig) {
+        info!("Registered ingest source {}", name);
+        // A source counts as reachable while pushes arrive before it goes stale.
+        self.state.metrics.record_poll_interval(name, Duration::from_secs(config.stale_after_secs));
+        self.sources.insert(name.to_string(), IngestSource {
+            token: config.tokens.get(name).cloned(),
+            last_update: Mutex::new(None),
+        });
+    }
+
+    async fn ingest(&self, headers: &HeaderMap, payload: IngestPayload) -> Result<(), (StatusCode, String)> {
+        let source = self.sources.get(&payload.source)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown ingest source '{}'", payload.source)))?;
+
+        if let Some(expected) = &source.token {
+            let provided = headers.get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "));
+            if !provided.is_some_and(|token| auth::constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+                return Err((StatusCode::UNAUTHORIZED, "Missing or invalid ingest token".to_string()));
+            }
+        }
+
+        let report = payload.into_report().map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+        let name = report.source.clone();
+        *source.last_update.lock().unwrap() = Some(Instant::now());
+        self.reports.send(report).await
+            .map_err(|_| (StatusCode::SERVICE_UNAVAILABLE, "Source aggregator is not running".to_string()))?;
+        self.state.metrics.record_push(&name);
+        Ok(())
+    }
+}
+
+/// Marks an ingest source as unavailable once no update has arrived for
+/// `stale_after`; ingest sources cannot handle commands, so those are dropped.
+pub fn watch_ingest_source(registry: Arc<IngestRegistry>, name: String, stale_after: Duration, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STALENESS_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        warn!("Ingest source {} cannot handle command {:?}", name, command);
+                        continue;
+                    }
+                    None => break,
+                },
+            }
+
+            let Some(source) = registry.sources.get(&name) else {
+                break;
+            };
+            let is_stale = {
+                let mut last_update = source.last_update.lock().unwrap();
+                let is_stale = last_update.is_some_and(|last| last.elapsed() > stale_after);
+                if is_stale {
+                    *last_update = None;
+                }
+                is_stale
+            };
+            if is_stale {
+                info!("Ingest source {} went stale after {}s without updates", name, stale_after.as_secs());
+                if registry.reports.send(SourceReport::without_track(&name, PlayerState::Unavailable)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// `POST /api/ingest` and the `/api/ingest/ws` streaming variant.
+pub fn routes(registry: Arc<IngestRegistry>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/ingest", post(ingest_handler))
+        .route("/api/ingest/ws", any(ingest_ws_handler))
+        .layer(Extension(registry))
+}
+
+async fn ingest_handler(Extension(registry): Extension<Arc<IngestRegistry>>, headers: HeaderMap, Json(payload): Json<IngestPayload>) -> Response {
+    match registry.ingest(&headers, payload).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err((status, message)) => (status, Json(serde_json::json!({ "error": message }))).into_response(),
+    }
+}
+
+async fn ingest_ws_handler(ws: WebSocketUpgrade, headers: HeaderMap, Extension(registry): Extension<Arc<IngestRegistry>>) -> Response {
+    ws.on_upgrade(move |socket| ingest_socket(socket, headers, registry))
+}
+
+async fn ingest_socket(mut socket: WebSocket, headers: HeaderMap, registry: Arc<IngestRegistry>) {
+    info!("New ingest WebSocket connection");
+    while let Some(message) = socket.next().await {
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => continue,
+        };
+
+        let result = match serde_json::from_str::<IngestPayload>(&text) {
+            Ok(payload) => registry.ingest(&headers, payload).await,
+            Err(e) => Err((StatusCode::BAD_REQUEST, format!("Invalid ingest payload: {}", e))),
+        };
+        if let Err((status, message)) = result {
+            warn!("Rejected ingest message ({}): {}", status, message);
+            let error = serde_json::json!({ "error": message }).to_string();
+            if socket.send(Message::Text(error.into())).await.is_err() {
+                break;
+            }
+        }
+    }
+    info!("Ingest WebSocket connection closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(state: &Arc<AppState>) -> (IngestRegistry, mpsc::Receiver<SourceReport>) {
+        let (reports, receiver) = mpsc::channel(4);
+        let mut registry = IngestRegistry::new(state.clone(), reports);
+        let config = IngestConfig { tokens: HashMap::from([("deck".to_string(), "secret".to_string())]), ..IngestConfig::default() };
+        registry.add_source("deck", &config);
+        (registry, receiver)
+    }
+
+    fn payload() -> IngestPayload {
+        serde_json::from_value(serde_json::json!({ "source": "deck", "track_name": "Roads", "artist_name": "Portishead" })).unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap())])
+    }
+
+    #[tokio::test]
+    async fn accepted_pushes_make_the_source_reachable() {
+        let state = AppState::test();
+        let (registry, mut receiver) = registry(&state);
+        assert_eq!(state.metrics.reachable_sources(Duration::from_secs(5)).get("deck"), Some(&false));
+
+        registry.ingest(&bearer("secret"), payload()).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap().track.unwrap().track_name, "Roads");
+        assert_eq!(state.metrics.reachable_sources(Duration::from_secs(5)).get("deck"), Some(&true));
+    }
+
+    #[tokio::test]
+    async fn rejected_pushes_leave_the_source_unreachable() {
+        let state = AppState::test();
+        let (registry, _receiver) = registry(&state);
+
+        let (status, _) = registry.ingest(&bearer("wrong"), payload()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = registry.ingest(&bearer("secret"), serde_json::from_str(r#"{"source":"deck"}"#).unwrap()).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(state.metrics.reachable_sources(Duration::from_secs(5)).get("deck"), Some(&false));
+    }
+}
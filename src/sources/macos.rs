@@ -0,0 +1,121 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use tokio::sync::{mpsc, oneshot, Notify};
+use tracing::{debug, info, warn};
+
+use crate::{config::PollingConfig, models::{AppState, PlayerCommand, PlayerState}, sources::{macos_bridge::{self, ScriptablePlayer}, macos_mapping::{self, PlayerSnapshot}, schedule::PollScheduler, SourceReport, RADIO_PLACEHOLDER}};
+
+pub use crate::sources::macos_mapping::{APPLE_MUSIC_BUNDLE_ID, SPOTIFY_BUNDLE_ID};
+
+/// Work for the thread that owns a player's ScriptingBridge handle.
+enum Request {
+    Snapshot(oneshot::Sender<Option<PlayerSnapshot>>),
+    Execute(PlayerCommand, oneshot::Sender<bool>),
+}
+
+/// Watches a ScriptingBridge-capable player (Music, Spotify) and reports
+/// what it is playing to the source aggregator. The player is polled when it
+/// posts a change notification and otherwise on an adaptive schedule.
+pub fn listen_for_player(state: Arc<AppState>, name: String, bundle_id: &'static str, polling: &PollingConfig, reports: mpsc::Sender<SourceReport>, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    info!("Starting track listener for {} ({})", name, bundle_id);
+
+    let (requests, request_receiver) = std::sync::mpsc::channel();
+    let thread_name = name.clone();
+    let spawned = std::thread::Builder::new()
+        .name(format!("player:{}", name))
+        .spawn(move || serve_player(&thread_name, bundle_id, request_receiver));
+    if let Err(e) = spawned {
+        warn!("Failed to start the player thread for {}: {}", name, e);
+        return;
+    }
+
+    let changed = Arc::new(Notify::new());
+    if let Some(notification) = macos_mapping::change_notification(bundle_id) {
+        let changed = changed.clone();
+        if let Err(e) = macos_bridge::observe_notification(notification, move || changed.notify_one()) {
+            warn!("Failed to observe {} notifications, polling only: {}", name, e);
+        }
+    }
+
+    let scheduler = PollScheduler::new(polling);
+    tokio::spawn(async move {
+        let mut reported_radio = false;
+        let mut last_state = None;
+        let mut delay = Duration::ZERO;
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = changed.notified() => debug!("{} posted a change notification", name),
+                command = commands.recv() => {
+                    let Some(command) = command else { break };
+                    match ask(&requests, |reply| Request::Execute(command, reply)).await {
+                        Some(true) => info!("Executed player command {:?} on {}", command, name),
+                        Some(false) => warn!("Player {} rejected command {:?}", name, command),
+                        None => break,
+                    }
+                }
+            }
+
+            let poll_started = Instant::now();
+            let Some(snapshot) = ask(&requests, Request::Snapshot).await else {
+                break;
+            };
+            state.metrics.record_poll(&name, poll_started.elapsed());
+
+            let player_state = snapshot.as_ref().map_or(PlayerState::NotRunning, |snapshot| snapshot.player_state());
+            if last_state != Some(player_state) {
+                match player_state {
+                    PlayerState::Unavailable => warn!("{} did not report its state; check that Rusty-Tapes may control it under Privacy & Security > Automation", name),
+                    _ => info!("{} is {}", name, player_state.as_str()),
+                }
+                last_state = Some(player_state);
+            }
+
+            let track = snapshot.as_ref()
+                .filter(|_| player_state.has_track())
+                .map(|snapshot| macos_mapping::map_track(snapshot, bundle_id, &name));
+            if let Some(track) = &track {
+                let is_radio = track.track_name == RADIO_PLACEHOLDER;
+                if is_radio && !reported_radio {
+                    info!("Current track name is null. Most likely radio or mix is playing.");
+                }
+                reported_radio = is_radio;
+            }
+
+            let report = SourceReport { source: name.clone(), state: player_state, track };
+            delay = scheduler.next_delay(&report);
+            state.metrics.record_poll_interval(&name, delay);
+            if reports.send(report).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Sends a request to the player thread and waits for its reply, or `None`
+/// if the thread has exited.
+async fn ask<T>(requests: &std::sync::mpsc::Sender<Request>, request: impl FnOnce(oneshot::Sender<T>) -> Request) -> Option<T> {
+    let (reply, response) = oneshot::channel();
+    requests.send(request(reply)).ok()?;
+    response.await.ok()
+}
+
+/// Owns the player handle, which can't leave the thread that created it,
+/// and answers requests until the listener goes away.
+fn serve_player(name: &str, bundle_id: &'static str, requests: std::sync::mpsc::Receiver<Request>) {
+    let Some(player) = ScriptablePlayer::new(bundle_id) else {
+        warn!("Player {} ({}) is not installed", name, bundle_id);
+        return;
+    };
+    for request in requests {
+        match request {
+            Request::Snapshot(reply) => {
+                let _ = reply.send(player.snapshot());
+            }
+            Request::Execute(command, reply) => {
+                let _ = reply.send(player.execute(command));
+            }
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{ffi::{c_void, CStr}, os::raw::c_char, ptr};
+
+use objc::{class, msg_send, rc::{autoreleasepool, StrongPtr}, runtime::{Object, Sel, BOOL, NO}, sel, sel_impl, Message};
+
+use crate::{models::PlayerCommand, sources::macos_mapping::{PlayerSnapshot, RawTrack}};
+
+#[link(name = "ScriptingBridge", kind = "framework")]
+extern "C" {}
+
+type NotificationCallback = extern "C" fn(*const c_void, *mut c_void, *const c_void, *const c_void, *const c_void);
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFNotificationCenterGetDistributedCenter() -> *const c_void;
+    fn CFNotificationCenterAddObserver(center: *const c_void, observer: *const c_void, callback: NotificationCallback, name: *const c_void, object: *const c_void, suspension_behavior: isize);
+    fn CFRunLoopRun();
+}
+
+const CF_NOTIFICATION_DELIVER_IMMEDIATELY: isize = 4;
+
+type Id = *mut Object;
+
+const NS_UTF8_STRING_ENCODING: usize = 4;
+
+/// A ScriptingBridge handle to a scriptable player such as Music or Spotify.
+///
+/// Every call runs in its own autorelease pool and checks `isRunning` first,
+/// since messaging a player that is not running would launch it. The handle
+/// is not `Send`, so create it on the thread that polls the player.
+pub struct ScriptablePlayer {
+    app: StrongPtr,
+}
+
+impl ScriptablePlayer {
+    /// Returns `None` if no application with `bundle_id` is installed.
+    pub fn new(bundle_id: &str) -> Option<Self> {
+        autoreleasepool(|| unsafe {
+            let identifier = ns_string(bundle_id);
+            let app: Id = msg_send![class!(SBApplication), applicationWithBundleIdentifier: *identifier];
+            (!app.is_null()).then(|| ScriptablePlayer { app: StrongPtr::retain(app) })
+        })
+    }
+
+    fn is_running(&self) -> bool {
+        let running: BOOL = unsafe { msg_send![*self.app, isRunning] };
+        running != NO
+    }
+
+    /// Reads the player state and current track, or `None` if the player is
+    /// not running.
+    pub fn snapshot(&self) -> Option<PlayerSnapshot> {
+        autoreleasepool(|| unsafe {
+            if !self.is_running() {
+                return None;
+            }
+            let app = *self.app;
+            let track = value(app, "currentTrack").map(|track| RawTrack {
+                name: value(track, "name").and_then(|name| string(name)),
+                artist: value(track, "artist").and_then(|artist| string(artist)),
+                album: value(track, "album").and_then(|album| string(album)),
+                genre: value(track, "genre").and_then(|genre| string(genre)),
+                duration: value(track, "duration").and_then(|duration| number(duration, sel!(doubleValue))),
+                favourited: value(track, "favorited").and_then(|favourited| number::<BOOL>(favourited, sel!(boolValue))).map(|favourited| favourited != NO),
+                played_count: value(track, "playedCount").and_then(|count| number(count, sel!(longLongValue))),
+            });
+            Some(PlayerSnapshot {
+                state: value(app, "playerState").and_then(|state| number(state, sel!(longLongValue))),
+                position: value(app, "playerPosition").and_then(|position| number(position, sel!(doubleValue))),
+                track,
+            })
+        })
+    }
+
+    /// Sends a playback command, returning whether the player accepted it.
+    pub fn execute(&self, command: PlayerCommand) -> bool {
+        if !self.is_running() {
+            return false;
+        }
+        match command {
+            PlayerCommand::Play => self.snapshot().is_some_and(|snapshot| snapshot.is_playing()) || self.perform("playpause"),
+            PlayerCommand::Pause => self.perform("pause"),
+            PlayerCommand::PlayPause => self.perform("playpause"),
+            PlayerCommand::Next => self.perform("nextTrack"),
+            PlayerCommand::Previous => self.perform("previousTrack"),
+            PlayerCommand::Seek(position) => autoreleasepool(|| unsafe {
+                let key = ns_string("playerPosition");
+                let number: Id = msg_send![class!(NSNumber), numberWithDouble: position];
+                (**self.app).send_message::<(Id, Id), ()>(sel!(setValue:forKey:), (number, *key)).is_ok()
+            }),
+        }
+    }
+
+    fn perform(&self, selector: &str) -> bool {
+        autoreleasepool(|| unsafe {
+            let selector = Sel::register(selector);
+            let responds: BOOL = msg_send![*self.app, respondsToSelector: selector];
+            responds != NO && (**self.app).send_message::<(), ()>(selector, ()).is_ok()
+        })
+    }
+}
+
+/// Calls `on_change` whenever `notification` is posted to the distributed
+/// notification center. Delivery needs a run loop, so this spawns a thread
+/// that runs one for the rest of the process.
+pub fn observe_notification(notification: &'static str, on_change: impl Fn() + Send + 'static) -> std::io::Result<()> {
+    std::thread::Builder::new().name(format!("notify:{}", notification)).spawn(move || unsafe {
+        // Leaked on purpose: the observer is registered until the process exits.
+        let observer: *mut Box<dyn Fn()> = Box::into_raw(Box::new(Box::new(on_change)));
+        autoreleasepool(|| {
+            let name = ns_string(notification);
+            CFNotificationCenterAddObserver(CFNotificationCenterGetDistributedCenter(), observer as *const c_void,
+                notified, *name as *const c_void, ptr::null(), CF_NOTIFICATION_DELIVER_IMMEDIATELY);
+        });
+        CFRunLoopRun();
+    })?;
+    Ok(())
+}
+
+extern "C" fn notified(_center: *const c_void, observer: *mut c_void, _name: *const c_void, _object: *const c_void, _user_info: *const c_void) {
+    let on_change = unsafe { &*(observer as *const Box<dyn Fn()>) };
+    on_change();
+}
+
+/// An owned `NSString` copy of `value`, released when dropped.
+unsafe fn ns_string(value: &str) -> StrongPtr {
+    let string: Id = msg_send![class!(NSString), alloc];
+    let string: Id = msg_send![string, initWithBytes: value.as_ptr() length: value.len() encoding: NS_UTF8_STRING_ENCODING];
+    StrongPtr::new(string)
+}
+
+/// `valueForKey:`, returning `None` for nil and for keys the player's
+/// scripting dictionary doesn't define, which raise instead.
+unsafe fn value(object: Id, key: &str) -> Option<Id> {
+    let key = ns_string(key);
+    match (*object).send_message::<(Id,), Id>(sel!(valueForKey:), (*key,)) {
+        Ok(value) if !value.is_null() => Some(value),
+        _ => None,
+    }
+}
+
+/// Unboxes an `NSNumber` with `selector`, e.g. `doubleValue`.
+unsafe fn number<T: 'static>(value: Id, selector: Sel) -> Option<T> {
+    let responds: BOOL = msg_send![value, respondsToSelector: selector];
+    if responds == NO {
+        return None;
+    }
+    (*value).send_message::<(), T>(selector, ()).ok()
+}
+
+/// Copies an `NSString` into a Rust `String`; the UTF-8 buffer belongs to the
+/// current autorelease pool.
+unsafe fn string(value: Id) -> Option<String> {
+    let is_string: BOOL = msg_send![value, isKindOfClass: class!(NSString)];
+    if is_string == NO {
+        return None;
+    }
+    let utf8: *const c_char = msg_send![value, UTF8String];
+    (!utf8.is_null()).then(|| CStr::from_ptr(utf8).to_string_lossy().into_owned())
+}
@@ -0,0 +1,159 @@
+use crate::{models::{PlayerState, TrackInfo}, sources::RADIO_PLACEHOLDER};
+
+pub const APPLE_MUSIC_BUNDLE_ID: &str = "com.apple.Music";
+pub const SPOTIFY_BUNDLE_ID: &str = "com.spotify.client";
+
+/// The distributed notification a player posts whenever its state or track
+/// changes.
+pub fn change_notification(bundle_id: &str) -> Option<&'static str> {
+    match bundle_id {
+        APPLE_MUSIC_BUNDLE_ID => Some("com.apple.Music.playerInfo"),
+        SPOTIFY_BUNDLE_ID => Some("com.spotify.client.PlaybackStateChanged"),
+        _ => None,
+    }
+}
+
+/// The four-character codes both players use for `playerState`.
+pub const PLAYING: i64 = 1800426320; // kPSP
+pub const PAUSED: i64 = 1800426352; // kPSp
+pub const STOPPED: i64 = 1800426323; // kPSS
+pub const FAST_FORWARDING: i64 = 1800426310; // kPSF
+pub const REWINDING: i64 = 1800426322; // kPSR
+
+/// Properties read from a player's current track; `None` when the player's
+/// scripting dictionary doesn't define one or it returned nil.
+#[derive(Debug, Default)]
+pub struct RawTrack {
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub duration: Option<f64>,
+    pub favourited: Option<bool>,
+    pub played_count: Option<i64>,
+}
+
+/// What a single poll read from a running player.
+#[derive(Debug, Default)]
+pub struct PlayerSnapshot {
+    pub state: Option<i64>,
+    pub position: Option<f64>,
+    pub track: Option<RawTrack>,
+}
+
+impl PlayerSnapshot {
+    pub fn is_playing(&self) -> bool {
+        self.state == Some(PLAYING)
+    }
+
+    /// A running player that won't tell us its state has most likely been
+    /// denied the Automation permission.
+    pub fn player_state(&self) -> PlayerState {
+        match self.state {
+            Some(PLAYING | FAST_FORWARDING | REWINDING) => PlayerState::Playing,
+            Some(PAUSED) => PlayerState::Paused,
+            Some(STOPPED) => PlayerState::Stopped,
+            Some(_) | None => PlayerState::Unavailable,
+        }
+    }
+}
+
+/// Maps a snapshot to a `TrackInfo`, falling back to the radio placeholder
+/// when the player has no track metadata (e.g. radio stations and mixes).
+pub fn map_track(snapshot: &PlayerSnapshot, bundle_id: &str, source: &str) -> TrackInfo {
+    let Some(track) = snapshot.track.as_ref().filter(|track| track.name.is_some()) else {
+        return radio_placeholder(source);
+    };
+    let or_unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "Unknown".to_string());
+
+    let mut duration = track.duration.unwrap_or(0.0);
+    // Spotify reports track durations in milliseconds.
+    if bundle_id == SPOTIFY_BUNDLE_ID {
+        duration /= 1000.0;
+    }
+
+    TrackInfo {
+        track_name: or_unknown(&track.name),
+        artist_name: or_unknown(&track.artist),
+        progress: snapshot.position.unwrap_or(0.0),
+        duration: duration as f32,
+        genre: or_unknown(&track.genre),
+        favourited: track.favourited.unwrap_or(false),
+        played_count: track.played_count.unwrap_or(0) as i32,
+        album: or_unknown(&track.album),
+        source: source.to_string(),
+        artwork_url: None,
+        timing: None,
+        state: None,
+    }
+}
+
+fn radio_placeholder(source: &str) -> TrackInfo {
+    TrackInfo {
+        track_name: RADIO_PLACEHOLDER.to_string(),
+        artist_name: "Unknown".to_string(),
+        progress: 0.0,
+        duration: 0.0,
+        genre: "Unknown".to_string(),
+        favourited: false,
+        played_count: 0,
+        album: "Unknown".to_string(),
+        source: source.to_string(),
+        artwork_url: None,
+        timing: None,
+        state: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(name: Option<&str>, duration: f64) -> PlayerSnapshot {
+        PlayerSnapshot {
+            state: Some(PLAYING),
+            position: Some(42.0),
+            track: Some(RawTrack {
+                name: name.map(str::to_string),
+                artist: Some("Portishead".to_string()),
+                duration: Some(duration),
+                played_count: Some(3),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn spotify_durations_are_converted_from_milliseconds() {
+        let spotify = map_track(&snapshot(Some("Roads"), 305_000.0), SPOTIFY_BUNDLE_ID, "spotify_desktop");
+        assert_eq!(spotify.duration, 305.0);
+        let apple_music = map_track(&snapshot(Some("Roads"), 305.0), APPLE_MUSIC_BUNDLE_ID, "apple_music");
+        assert_eq!(apple_music.duration, 305.0);
+        assert_eq!(apple_music.progress, 42.0);
+        assert_eq!(apple_music.played_count, 3);
+        assert_eq!(apple_music.album, "Unknown");
+    }
+
+    #[test]
+    fn tracks_without_a_name_use_the_radio_placeholder() {
+        let track = map_track(&snapshot(None, 0.0), APPLE_MUSIC_BUNDLE_ID, "apple_music");
+        assert_eq!(track.track_name, RADIO_PLACEHOLDER);
+        assert_eq!(track.artist_name, "Unknown");
+
+        let no_track = PlayerSnapshot { state: Some(PLAYING), ..Default::default() };
+        assert_eq!(map_track(&no_track, APPLE_MUSIC_BUNDLE_ID, "apple_music").track_name, RADIO_PLACEHOLDER);
+    }
+
+    #[test]
+    fn maps_player_states() {
+        let state = |state| PlayerSnapshot { state, ..Default::default() }.player_state();
+        assert_eq!(state(Some(PLAYING)), PlayerState::Playing);
+        assert_eq!(state(Some(FAST_FORWARDING)), PlayerState::Playing);
+        assert_eq!(state(Some(REWINDING)), PlayerState::Playing);
+        assert_eq!(state(Some(PAUSED)), PlayerState::Paused);
+        assert_eq!(state(Some(STOPPED)), PlayerState::Stopped);
+        assert_eq!(state(Some(0)), PlayerState::Unavailable);
+        // No state usually means the Automation permission was denied.
+        assert_eq!(state(None), PlayerState::Unavailable);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{sync::Arc, time::{Duration, Instant}};
+
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::{models::{AppState, PlayerCommand, PlayerState, TrackInfo}, sources::SourceReport};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// (track, artist, album, genre, duration in seconds)
+const PLAYLIST: [(&str, &str, &str, &str, f32); 4] = [
+    ("Tape Hiss", "The Reels", "Side A", "Ambient", 95.0),
+    ("Auto Reverse", "The Reels", "Side A", "Ambient", 128.0),
+    ("Chrome Dioxide", "Ferric Oxide", "Type II", "Electronic", 172.0),
+    ("Dolby NR", "Ferric Oxide", "Type II", "Electronic", 143.0),
+];
+
+/// A fake player that loops over a small playlist, for developing overlays
+/// and sinks without a real player (e.g. on Linux).
+struct MockPlayer {
+    index: usize,
+    progress: f64,
+    playing: bool,
+}
+
+impl MockPlayer {
+    fn track(&self, source: &str) -> TrackInfo {
+        let (track_name, artist_name, album, genre, duration) = PLAYLIST[self.index];
+        TrackInfo {
+            track_name: track_name.to_string(),
+            artist_name: artist_name.to_string(),
+            progress: self.progress,
+            duration,
+            genre: genre.to_string(),
+            favourited: self.index.is_multiple_of(2),
+            played_count: self.index as i32 + 1,
+            album: album.to_string(),
+            source: source.to_string(),
+            artwork_url: None,
+            timing: None,
+            state: None,
+        }
+    }
+
+    fn skip(&mut self, offset: isize) {
+        self.index = (self.index as isize + offset).rem_euclid(PLAYLIST.len() as isize) as usize;
+        self.progress = 0.0;
+    }
+
+    fn advance(&mut self, elapsed: f64) {
+        if !self.playing {
+            return;
+        }
+        self.progress += elapsed;
+        if self.progress >= PLAYLIST[self.index].4 as f64 {
+            self.skip(1);
+        }
+    }
+
+    fn execute(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::Play => self.playing = true,
+            PlayerCommand::Pause => self.playing = false,
+            PlayerCommand::PlayPause => self.playing = !self.playing,
+            PlayerCommand::Next => self.skip(1),
+            PlayerCommand::Previous => self.skip(-1),
+            PlayerCommand::Seek(position) => self.progress = position.min(PLAYLIST[self.index].4 as f64),
+        }
+    }
+}
+
+pub fn listen_for_mock(state: Arc<AppState>, name: String, reports: mpsc::Sender<SourceReport>, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    info!("Starting mock source {}", name);
+
+    tokio::spawn(async move {
+        let mut player = MockPlayer { index: 0, progress: 0.0, playing: true };
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        let mut last_tick = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                command = commands.recv() => match command {
+                    Some(command) => {
+                        player.execute(command);
+                        info!("Executed player command {:?} on {}", command, name);
+                    }
+                    None => break,
+                },
+            }
+
+            player.advance(last_tick.elapsed().as_secs_f64());
+            last_tick = Instant::now();
+            state.metrics.record_poll(&name, Duration::ZERO);
+
+            let player_state = if player.playing { PlayerState::Playing } else { PlayerState::Paused };
+            let report = SourceReport { source: name.clone(), state: player_state, track: Some(player.track(&name)) };
+            if reports.send(report).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{config::Config, models::SourcePolicy, sources::start_sources};
+
+    #[test]
+    fn loops_over_the_playlist() {
+        let mut player = MockPlayer { index: 0, progress: 0.0, playing: true };
+        player.execute(PlayerCommand::Previous);
+        assert_eq!(player.index, PLAYLIST.len() - 1);
+
+        player.execute(PlayerCommand::Seek(1000.0));
+        assert_eq!(player.progress, 143.0);
+        player.advance(1.0);
+        assert_eq!((player.index, player.progress), (0, 0.0));
+
+        player.execute(PlayerCommand::PlayPause);
+        player.advance(10.0);
+        assert_eq!(player.progress, 0.0);
+    }
+
+    /// Runs the mock source through the aggregator into the broadcast.
+    #[tokio::test]
+    async fn smoke_test() {
+        let state = AppState::test();
+        let mut events = state.client_sender.subscribe();
+        let _routes = start_sources(state.clone(), &["mock".to_string()], SourcePolicy::OnlyPlaying, &Config::default()).unwrap();
+
+        let mut next_event = async || tokio::time::timeout(Duration::from_secs(5), events.recv()).await
+            .expect("an event within 5s").expect("an open broadcast");
+        let first = next_event().await;
+        assert_eq!(first.track.track_name, "Tape Hiss");
+        assert_eq!(first.track.source, "mock");
+        assert!(state.playback().playing);
+
+        assert!(state.send_player_command(PlayerCommand::Next));
+        let second = next_event().await;
+        assert_eq!(second.track.track_name, "Auto Reverse");
+        assert_eq!(second.id, first.id + 1);
+
+        assert!(state.send_player_command(PlayerCommand::Pause));
+        let paused = next_event().await;
+        assert!(paused.track.is_paused());
+        assert_eq!(state.playback().state, PlayerState::Paused);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{collections::BTreeMap, sync::{atomic::Ordering, Arc}, time::{Duration, Instant, SystemTime}};
+
+use axum::Router;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+use crate::{config::Config, models::{AppState, PlayerState, SourcePolicy, TrackEventKind, TrackInfo}, playback::{self, Observation, PlaybackTracker, Transition}};
+
+mod ingest;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "macos")]
+mod macos_bridge;
+// Kept on every platform so that its mapping tests run everywhere.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+mod macos_mapping;
+mod mock;
+mod mpd;
+#[cfg(target_os = "linux")]
+mod mpris;
+// Kept on every platform so that its mapping tests run everywhere.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+mod mpris_mapping;
+mod relay;
+mod schedule;
+mod spotify_web;
+
+pub const APPLE_MUSIC: &str = "apple_music";
+pub const SPOTIFY_DESKTOP: &str = "spotify_desktop";
+pub const SPOTIFY_WEB: &str = "spotify_web";
+pub const MPD: &str = "mpd";
+pub const MPRIS: &str = "mpris";
+pub const RELAY: &str = "relay";
+pub const MOCK: &str = "mock";
+/// The track name reported while a radio station or mix without track
+/// metadata is playing.
+pub const RADIO_PLACEHOLDER: &str = "Radio/Mix";
+/// Prefix for sources fed by external players through `/api/ingest`.
+pub const INGEST_PREFIX: &str = "ingest:";
+/// Prefix for MPRIS sources bound to one player, e.g. `mpris:vlc`.
+pub const MPRIS_PREFIX: &str = "mpris:";
+
+const REPORT_QUEUE_CAPACITY: usize = 64;
+
+/// What a source observed on its latest poll.
+#[derive(Clone, Debug)]
+pub struct SourceReport {
+    pub source: String,
+    pub state: PlayerState,
+    /// The current track; sources may omit it while paused
+    pub track: Option<TrackInfo>,
+}
+
+impl SourceReport {
+    /// A report for a player that has no track in `state`.
+    pub fn without_track(source: &str, state: PlayerState) -> Self {
+        SourceReport { source: source.to_string(), state, track: None }
+    }
+}
+
+/// The latest known state of a single source, as exposed by `/api/sources`.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    pub state: PlayerState,
+    pub playing: bool,
+    pub track: Option<TrackInfo>,
+    pub active: bool,
+    pub last_report_secs: Option<u64>,
+    #[serde(skip)]
+    pub last_report: Option<Instant>,
+    #[serde(skip)]
+    pub started_playing: Option<Instant>,
+}
+
+impl SourceStatus {
+    pub fn new(name: &str) -> Self {
+        SourceStatus {
+            name: name.to_string(),
+            state: PlayerState::default(),
+            playing: false,
+            track: None,
+            active: false,
+            last_report_secs: None,
+            last_report: None,
+            started_playing: None,
+        }
+    }
+
+    fn identifier(&self) -> Option<String> {
+        self.track.as_ref().map(playback::identifier)
+    }
+
+    /// Refreshes the derived, serialised fields before returning a copy.
+    pub fn snapshot(&self) -> Self {
+        SourceStatus {
+            last_report_secs: self.last_report.map(|last| last.elapsed().as_secs()),
+            ..self.clone()
+        }
+    }
+}
+
+/// A human readable name for a source, used in presence and overlays.
+pub fn display_name(source: &str) -> &str {
+    match source {
+        APPLE_MUSIC | "" => "Apple Music",
+        SPOTIFY_DESKTOP | SPOTIFY_WEB => "Spotify",
+        MPD => "MPD",
+        MPRIS => "MPRIS",
+        other if other.starts_with(MPRIS_PREFIX) => "MPRIS",
+        MOCK => "Mock Player",
+        other => other,
+    }
+}
+
+/// Starts every named source and the aggregator that selects between them,
+/// returning any HTTP routes the sources need (e.g. login callbacks).
+pub fn start_sources(state: Arc<AppState>, requested_names: &[String], policy: SourcePolicy, config: &Config) -> Result<Router<Arc<AppState>>, String> {
+    let (reports, report_receiver) = mpsc::channel(REPORT_QUEUE_CAPACITY);
+    let mut routes = Router::new();
+    let mut ingest_registry = ingest::IngestRegistry::new(state.clone(), reports.clone());
+    let mut ingest_sources = Vec::new();
+    let names: Vec<String> = requested_names.iter()
+        .map(|name| name.strip_prefix(INGEST_PREFIX).unwrap_or(name).to_string())
+        .collect();
+
+    for (name, requested) in names.iter().zip(requested_names) {
+        let commands = register_source(&state, name);
+        if requested.starts_with(INGEST_PREFIX) {
+            ingest_registry.add_source(name, &config.ingest);
+            ingest_sources.push((name.clone(), commands));
+            continue;
+        }
+        match name.as_str() {
+            #[cfg(target_os = "macos")]
+            APPLE_MUSIC => macos::listen_for_player(state.clone(), name.clone(), macos::APPLE_MUSIC_BUNDLE_ID, &config.polling, reports.clone(), commands),
+            #[cfg(target_os = "macos")]
+            SPOTIFY_DESKTOP => macos::listen_for_player(state.clone(), name.clone(), macos::SPOTIFY_BUNDLE_ID, &config.polling, reports.clone(), commands),
+            #[cfg(not(target_os = "macos"))]
+            APPLE_MUSIC | SPOTIFY_DESKTOP => return Err(format!("The {} source is only available on macOS", name)),
+            SPOTIFY_WEB => {
+                let spotify_config = config.spotify.clone()
+                    .ok_or_else(|| "The spotify_web source requires a [spotify] section in the config file".to_string())?;
+                let spotify = spotify_web::SpotifyWeb::new(spotify_config);
+                routes = routes.merge(spotify_web::routes(spotify.clone()));
+                spotify_web::listen_for_spotify(state.clone(), name.clone(), spotify, &config.polling, reports.clone(), commands);
+            }
+            MPD => mpd::listen_for_mpd(state.clone(), name.clone(), config.mpd.clone().unwrap_or_default(), reports.clone(), commands),
+            RELAY => {
+                let relay_config = config.relay.clone()
+                    .ok_or_else(|| "The relay source requires a [relay] section in the config file".to_string())?;
+                relay::listen_for_relay(state.clone(), name.clone(), relay_config, reports.clone(), commands);
+            }
+            #[cfg(target_os = "linux")]
+            MPRIS => mpris::listen_for_mpris(state.clone(), name.clone(), None, reports.clone(), commands),
+            #[cfg(target_os = "linux")]
+            other if other.starts_with(MPRIS_PREFIX) => {
+                let player = other[MPRIS_PREFIX.len()..].to_string();
+                mpris::listen_for_mpris(state.clone(), name.clone(), Some(player), reports.clone(), commands);
+            }
+            #[cfg(not(target_os = "linux"))]
+            other if other == MPRIS || other.starts_with(MPRIS_PREFIX) => return Err("The mpris source is only available on Linux".to_string()),
+            MOCK => mock::listen_for_mock(state.clone(), name.clone(), reports.clone(), commands),
+            other => return Err(format!("Unknown source '{}'", other)),
+        }
+    }
+
+    if !ingest_registry.is_empty() {
+        let registry = Arc::new(ingest_registry);
+        let stale_after = Duration::from_secs(config.ingest.stale_after_secs);
+        for (name, commands) in ingest_sources {
+            ingest::watch_ingest_source(registry.clone(), name, stale_after, commands);
+        }
+        routes = routes.merge(ingest::routes(registry));
+    }
+
+    aggregate_sources(state, report_receiver, policy, names);
+    Ok(routes)
+}
+
+fn register_source(state: &AppState, name: &str) -> mpsc::UnboundedReceiver<crate::models::PlayerCommand> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    state.sources.lock().unwrap().insert(name.to_string(), SourceStatus::new(name));
+    state.source_commands.lock().unwrap().insert(name.to_string(), sender);
+    receiver
+}
+
+fn aggregate_sources(state: Arc<AppState>, mut reports: mpsc::Receiver<SourceReport>, policy: SourcePolicy, priority: Vec<String>) {
+    info!("Starting source aggregator with {:?} policy over {:?}", policy, priority);
+
+    tokio::spawn(async move {
+        let mut selected: Option<String> = None;
+        let mut tracker = PlaybackTracker::default();
+
+        while let Some(report) = reports.recv().await {
+            let (observation, player_state) = {
+                let mut sources = state.sources.lock().unwrap();
+                apply_report(&state, &mut sources, report);
+                selected = select_source(&sources, policy, &priority, selected.take());
+                for source in sources.values_mut() {
+                    source.active = selected.as_ref() == Some(&source.name);
+                }
+
+                let current = selected.as_ref().and_then(|name| sources.get(name));
+                // With nothing selected, report the state of the preferred source.
+                let player_state = current.or_else(|| priority.first().and_then(|name| sources.get(name)))
+                    .map(|source| source.state)
+                    .unwrap_or_default();
+                let observation = Observation {
+                    at: current.and_then(|source| source.last_report).unwrap_or_else(Instant::now),
+                    playing: current.is_some_and(|source| source.playing),
+                    track: current.and_then(|source| source.track.clone()),
+                };
+                (observation, player_state)
+            };
+
+            let reported_at = SystemTime::now() - observation.at.elapsed();
+            let Some(transition) = tracker.observe(observation) else {
+                if state.playback().state != player_state {
+                    state.update_playback(|playback| playback.state = player_state);
+                }
+                continue;
+            };
+            debug!("Playback transition: {:?}", transition);
+            state.record_play(&transition, reported_at);
+
+            if transition.is_new_play() {
+                state.metrics.track_changes.fetch_add(1, Ordering::Relaxed);
+            }
+
+            let kind = TrackEventKind::of(&transition);
+            let ended = transition.ended();
+            match transition {
+                Transition::Started(track) | Transition::Changed(track, _) | Transition::Resumed(track)
+                | Transition::Seeked(track) | Transition::Repeated(track) => {
+                    let track = TrackInfo { state: Some(player_state), ..track }.with_timing(reported_at, 1.0);
+                    state.broadcast(track.clone(), kind, ended, |playback| {
+                        playback.track = Some(track);
+                        playback.playing = true;
+                        playback.state = player_state;
+                        playback.updated_at = SystemTime::now();
+                    });
+                }
+                Transition::Paused(track) | Transition::Stopped(track, _) => {
+                    let now = SystemTime::now();
+                    state.broadcast(state_marker(&track, player_state).with_timing(now, 0.0), kind, ended, |playback| {
+                        playback.playing = false;
+                        playback.state = player_state;
+                        // Freeze the stored track where it was paused, or drop it
+                        // once the player has stopped or quit.
+                        playback.track = playback.track.take()
+                            .filter(|_| player_state.has_track())
+                            .map(|track| TrackInfo {
+                                progress: track.position_at(now),
+                                state: Some(player_state),
+                                ..track
+                            }.with_timing(now, 0.0));
+                    });
+                }
+            }
+        }
+    });
+}
+
+/// Updates a source's status from its report and notifies per-source
+/// subscribers when its track or play state changed.
+fn apply_report(state: &AppState, sources: &mut BTreeMap<String, SourceStatus>, report: SourceReport) {
+    let status = sources.entry(report.source.clone()).or_insert_with(|| SourceStatus::new(&report.source));
+    let previous_identifier = status.identifier();
+    let previous_track = status.track.clone();
+    let was_playing = status.playing;
+
+    status.last_report = Some(Instant::now());
+    status.state = report.state;
+    status.playing = report.state.is_playing();
+    // Keep the last track while paused, but not once the player has stopped or quit.
+    if report.track.is_some() || !report.state.has_track() {
+        status.track = report.track;
+    }
+    if status.playing && !was_playing {
+        status.started_playing = Some(Instant::now());
+    }
+
+    let track_changed = status.identifier() != previous_identifier;
+    match (&status.track, status.playing) {
+        (Some(track), true) if track_changed || !was_playing => {
+            state.publish_source(&status.name, track);
+        }
+        (Some(track), false) if was_playing => {
+            state.publish_source(&status.name, &state_marker(track, status.state));
+        }
+        // The track was just cleared, so announce the stop with the last one.
+        (None, _) => {
+            if let Some(track) = &previous_track {
+                state.publish_source(&status.name, &state_marker(track, status.state));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn select_source(sources: &BTreeMap<String, SourceStatus>, policy: SourcePolicy, priority: &[String], previous: Option<String>) -> Option<String> {
+    let in_priority_order = || priority.iter().filter_map(|name| sources.get(name));
+
+    match policy {
+        SourcePolicy::Priority => in_priority_order()
+            .find(|source| source.track.is_some())
+            .map(|source| source.name.clone()),
+        SourcePolicy::MostRecent => sources.values()
+            .filter(|source| source.track.is_some())
+            .max_by_key(|source| source.started_playing)
+            .map(|source| source.name.clone()),
+        SourcePolicy::OnlyPlaying => in_priority_order()
+            .find(|source| source.playing && source.track.is_some())
+            .map(|source| source.name.clone())
+            .or(previous),
+    }
+}
+
+/// Pause updates carry the last track with zeroed progress and a negative duration.
+pub fn pause_marker(track: &TrackInfo) -> TrackInfo {
+    TrackInfo {
+        progress: 0.0,
+        duration: -1.0,
+        ..track.clone()
+    }
+}
+
+/// A pause marker carrying the player's state, so that clients can tell a
+/// pause from a stop or a player that quit.
+pub fn state_marker(track: &TrackInfo, state: PlayerState) -> TrackInfo {
+    TrackInfo {
+        state: Some(state),
+        ..pause_marker(track)
+    }
+}
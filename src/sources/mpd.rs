@@ -0,0 +1,283 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream}, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::{config::MpdConfig, models::{AppState, PlayerCommand, PlayerState, TrackInfo}, sources::SourceReport};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long to stay idle before re-checking the connection, kept below the
+/// readiness timeout so an idle but healthy MPD still counts as reachable.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// A minimal client for the MPD text protocol.
+struct MpdConnection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl MpdConnection {
+    async fn connect(config: &MpdConfig) -> Result<Self, String> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await
+            .map_err(|e| format!("Failed to connect to MPD at {}:{}: {}", config.host, config.port, e))?;
+        let (reader, writer) = stream.into_split();
+        let mut connection = MpdConnection { lines: BufReader::new(reader).lines(), writer };
+
+        let greeting = connection.next_line().await?;
+        if !greeting.starts_with("OK MPD") {
+            return Err(format!("Unexpected MPD greeting: {}", greeting));
+        }
+        if let Some(password) = &config.password {
+            connection.command(&format!("password {}", quote(password))).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn next_line(&mut self) -> Result<String, String> {
+        self.lines.next_line().await
+            .map_err(|e| format!("Failed to read from MPD: {}", e))?
+            .ok_or_else(|| "MPD closed the connection".to_string())
+    }
+
+    async fn send(&mut self, command: &str) -> Result<(), String> {
+        self.writer.write_all(format!("{}\n", command).as_bytes()).await
+            .map_err(|e| format!("Failed to write to MPD: {}", e))
+    }
+
+    /// Reads `key: value` lines until the terminating `OK` or `ACK`.
+    async fn read_response(&mut self) -> Result<HashMap<String, String>, String> {
+        let mut fields = HashMap::new();
+        loop {
+            let line = self.next_line().await?;
+            if line == "OK" {
+                return Ok(fields);
+            }
+            if line.starts_with("ACK") {
+                return Err(format!("MPD error: {}", line));
+            }
+            if let Some((key, value)) = line.split_once(": ") {
+                fields.entry(key.to_string()).or_insert_with(|| value.to_string());
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str) -> Result<HashMap<String, String>, String> {
+        self.send(command).await?;
+        self.read_response().await
+    }
+
+    async fn query(&mut self, source: &str) -> Result<(PlayerState, Option<TrackInfo>), String> {
+        let status = self.command("status").await?;
+        let song = self.command("currentsong").await?;
+
+        let player_state = match status.get("state").map(String::as_str) {
+            Some("play") => PlayerState::Playing,
+            Some("pause") => PlayerState::Paused,
+            _ => PlayerState::Stopped,
+        };
+        let track = song.get("file").map(|file| map_song(&song, &status, file, source));
+        Ok((player_state, track))
+    }
+}
+
+/// Quotes an argument as required by the MPD protocol.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn map_song(song: &HashMap<String, String>, status: &HashMap<String, String>, file: &str, source: &str) -> TrackInfo {
+    let field = |key: &str| song.get(key).cloned().unwrap_or_else(|| "Unknown".to_string());
+    // Streams and untagged files have no title, so fall back to the file name.
+    let track_name = song.get("Title").cloned().unwrap_or_else(|| {
+        file.rsplit('/').next().unwrap_or(file).to_string()
+    });
+    let duration = song.get("duration").or_else(|| song.get("Time"))
+        .or_else(|| status.get("duration"))
+        .and_then(|value| value.parse::<f32>().ok())
+        .unwrap_or(0.0);
+
+    TrackInfo {
+        track_name,
+        artist_name: song.get("Artist").or_else(|| song.get("Name")).cloned().unwrap_or_else(|| "Unknown".to_string()),
+        progress: status.get("elapsed").and_then(|value| value.parse::<f64>().ok()).unwrap_or(0.0),
+        duration,
+        genre: field("Genre"),
+        favourited: false,
+        played_count: 0,
+        album: field("Album"),
+        source: source.to_string(),
+        artwork_url: None,
+        timing: None,
+        state: None,
+    }
+}
+
+fn command_line(command: PlayerCommand) -> String {
+    match command {
+        PlayerCommand::Play => "play".to_string(),
+        PlayerCommand::Pause => "pause 1".to_string(),
+        PlayerCommand::PlayPause => "pause".to_string(),
+        PlayerCommand::Next => "next".to_string(),
+        PlayerCommand::Previous => "previous".to_string(),
+        PlayerCommand::Seek(position) => format!("seekcur {:.3}", position),
+    }
+}
+
+enum Wake {
+    Changed,
+    Command(PlayerCommand),
+    Heartbeat,
+}
+
+pub fn listen_for_mpd(state: Arc<AppState>, name: String, config: MpdConfig, reports: mpsc::Sender<SourceReport>, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    info!("Starting MPD source {} for {}:{}", name, config.host, config.port);
+
+    tokio::spawn(async move {
+        loop {
+            let result = run_connection(&state, &name, &config, &reports, &mut commands).await;
+            if reports.is_closed() {
+                break;
+            }
+            if let Err(e) = result {
+                warn!("MPD source {} disconnected: {}", name, e);
+                state.metrics.set_integration_connected("mpd", false);
+                state.metrics.record_integration_error("mpd", &e);
+            }
+            let _ = reports.send(SourceReport::without_track(&name, PlayerState::Unavailable)).await;
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+/// Reports the player state, then waits in `idle player` until MPD signals
+/// a change, a command arrives, or the heartbeat interval elapses.
+async fn run_connection(state: &AppState, name: &str, config: &MpdConfig, reports: &mpsc::Sender<SourceReport>, commands: &mut mpsc::UnboundedReceiver<PlayerCommand>) -> Result<(), String> {
+    let mut connection = MpdConnection::connect(config).await?;
+    info!("Connected to MPD at {}:{}", config.host, config.port);
+    state.metrics.set_integration_connected("mpd", true);
+
+    loop {
+        let poll_started = Instant::now();
+        let (player_state, track) = connection.query(name).await?;
+        state.metrics.record_poll(name, poll_started.elapsed());
+        if reports.send(SourceReport { source: name.to_string(), state: player_state, track }).await.is_err() {
+            return Ok(());
+        }
+
+        connection.send("idle player").await?;
+        let wake = tokio::select! {
+            response = connection.read_response() => {
+                response?;
+                Wake::Changed
+            }
+            command = commands.recv() => match command {
+                Some(command) => Wake::Command(command),
+                None => return Ok(()),
+            },
+            _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => Wake::Heartbeat,
+        };
+
+        if !matches!(wake, Wake::Changed) {
+            // `noidle` ends the idle, answered by any pending changes and `OK`.
+            connection.command("noidle").await?;
+        }
+        if let Wake::Command(command) = wake {
+            match connection.command(&command_line(command)).await {
+                Ok(_) => info!("Executed player command {:?} on {}", command, name),
+                Err(e) => warn!("MPD rejected command {:?}: {}", command, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    /// Serves one connection on a local port, answering each command with
+    /// its scripted response and forwarding the commands it received.
+    async fn stub_mpd(script: &'static [(&'static str, &'static str)]) -> (MpdConfig, mpsc::UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MpdConfig { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port(), password: Some("pa\"ss".to_string()) };
+        let (received, commands) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"OK MPD 0.23.5\n").await.unwrap();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = script.iter().find(|(command, _)| line.starts_with(command)).map(|(_, response)| *response).unwrap_or("OK\n");
+                let _ = received.send(line);
+                writer.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (config, commands)
+    }
+
+    #[test]
+    fn maps_tagged_songs() {
+        let song = fields(&[("file", "music/roads.flac"), ("Title", "Roads"), ("Artist", "Portishead"), ("Album", "Dummy"), ("duration", "305.4")]);
+        let status = fields(&[("elapsed", "62.5")]);
+        let track = map_song(&song, &status, "music/roads.flac", "mpd");
+        assert_eq!(track.track_name, "Roads");
+        assert_eq!(track.album, "Dummy");
+        assert_eq!(track.duration, 305.4);
+        assert_eq!(track.progress, 62.5);
+        assert_eq!(track.genre, "Unknown");
+    }
+
+    #[test]
+    fn maps_streams_by_file_and_station_name() {
+        let song = fields(&[("file", "http://radio.example/stream/live.mp3"), ("Name", "Radio Example")]);
+        let track = map_song(&song, &HashMap::new(), "http://radio.example/stream/live.mp3", "mpd");
+        assert_eq!(track.track_name, "live.mp3");
+        assert_eq!(track.artist_name, "Radio Example");
+        assert_eq!(track.duration, 0.0);
+    }
+
+    #[test]
+    fn quotes_arguments() {
+        assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+    }
+
+    #[tokio::test]
+    async fn queries_status_and_waits_in_idle() {
+        static SCRIPT: &[(&str, &str)] = &[
+            ("status", "volume: 50\nstate: pause\nelapsed: 12.000\nduration: 305.400\nOK\n"),
+            ("currentsong", "file: roads.flac\nTitle: Roads\nArtist: Portishead\nOK\n"),
+            ("idle player", "changed: player\nOK\n"),
+            ("next", "ACK [55@0] {next} Not playing\n"),
+        ];
+        let (config, mut commands) = stub_mpd(SCRIPT).await;
+
+        let mut connection = MpdConnection::connect(&config).await.unwrap();
+        assert_eq!(commands.recv().await.unwrap(), r#"password "pa\"ss""#);
+
+        let (player_state, track) = connection.query("mpd").await.unwrap();
+        assert_eq!(player_state, PlayerState::Paused);
+        let track = track.unwrap();
+        assert_eq!((track.track_name.as_str(), track.duration, track.progress), ("Roads", 305.4, 12.0));
+
+        connection.send("idle player").await.unwrap();
+        let changes = connection.read_response().await.unwrap();
+        assert_eq!(changes.get("changed").map(String::as_str), Some("player"));
+
+        let error = connection.command(&command_line(PlayerCommand::Next)).await.unwrap_err();
+        assert!(error.contains("Not playing"));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_servers() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MpdConfig { host: "127.0.0.1".to_string(), port: listener.local_addr().unwrap().port(), password: None };
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.6\n").await.unwrap();
+        });
+        assert!(MpdConnection::connect(&config).await.is_err_and(|e| e.contains("Unexpected MPD greeting")));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use zbus::{fdo::DBusProxy, message::Type, proxy::CacheProperties, zvariant::{ObjectPath, OwnedValue, Value}, Connection, MatchRule, MessageStream, Proxy};
+
+use crate::{models::{AppState, PlayerCommand, PlayerState}, sources::{mpris_mapping::{self, PlayerProperties}, SourceReport}};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long to wait for a change signal before re-reading the player, which
+/// also picks up players that started or quit in the meantime.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// A player found on the session bus and the properties read from it.
+struct Player {
+    proxy: Proxy<'static>,
+    properties: PlayerProperties,
+    track_id: Option<String>,
+}
+
+pub fn listen_for_mpris(state: Arc<AppState>, name: String, player: Option<String>, reports: mpsc::Sender<SourceReport>, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    info!("Starting MPRIS source {} for {}", name, player.as_deref().unwrap_or("any player"));
+
+    tokio::spawn(async move {
+        loop {
+            let result = run_connection(&state, &name, player.as_deref(), &reports, &mut commands).await;
+            if reports.is_closed() {
+                break;
+            }
+            if let Err(e) = result {
+                warn!("MPRIS source {} disconnected: {}", name, e);
+                state.metrics.set_integration_connected("mpris", false);
+                state.metrics.record_integration_error("mpris", &e);
+            }
+            let _ = reports.send(SourceReport::without_track(&name, PlayerState::Unavailable)).await;
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+/// Reports the player state, then waits until a player signals a property
+/// change, a command arrives, or the heartbeat interval elapses.
+async fn run_connection(state: &AppState, name: &str, player: Option<&str>, reports: &mpsc::Sender<SourceReport>, commands: &mut mpsc::UnboundedReceiver<PlayerCommand>) -> Result<(), String> {
+    let connection = Connection::session().await.map_err(|e| format!("Failed to connect to the session bus: {}", e))?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.DBus.Properties")
+        .and_then(|rule| rule.member("PropertiesChanged"))
+        .and_then(|rule| rule.path(OBJECT_PATH))
+        .map_err(|e| e.to_string())?
+        .build();
+    let mut changes = MessageStream::for_match_rule(rule, &connection, Some(16)).await
+        .map_err(|e| format!("Failed to watch MPRIS players: {}", e))?;
+    info!("Connected to the session bus for MPRIS");
+    state.metrics.set_integration_connected("mpris", true);
+
+    loop {
+        let poll_started = Instant::now();
+        let current = find_player(&connection, player).await.map_err(|e| format!("Failed to query MPRIS players: {}", e))?;
+        state.metrics.record_poll(name, poll_started.elapsed());
+        let report = match &current {
+            Some(current) => mpris_mapping::map_properties(&current.properties, name),
+            None => SourceReport::without_track(name, PlayerState::NotRunning),
+        };
+        if reports.send(report).await.is_err() {
+            return Ok(());
+        }
+
+        tokio::select! {
+            change = changes.next() => {
+                if change.is_none() {
+                    return Err("The session bus closed the connection".to_string());
+                }
+            }
+            command = commands.recv() => {
+                let Some(command) = command else { return Ok(()) };
+                let Some(current) = &current else {
+                    warn!("No MPRIS player to handle command {:?}", command);
+                    continue;
+                };
+                match execute_command(current, command).await {
+                    Ok(()) => info!("Executed player command {:?} on {}", command, name),
+                    Err(e) => warn!("MPRIS player rejected command {:?}: {}", command, e),
+                }
+            }
+            _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {}
+        }
+    }
+}
+
+/// Finds the requested player, preferring one that is playing when several
+/// players are running.
+async fn find_player(connection: &Connection, player: Option<&str>) -> zbus::Result<Option<Player>> {
+    let names = DBusProxy::new(connection).await?.list_names().await?;
+    let mut found = None;
+    for bus_name in names.iter().filter(|bus_name| mpris_mapping::is_player(bus_name.as_str(), player)) {
+        // Players can quit between listing and reading, so skip those.
+        let Ok(candidate) = read_player(connection, bus_name.to_string()).await else {
+            continue;
+        };
+        if candidate.properties.player_state() == PlayerState::Playing {
+            return Ok(Some(candidate));
+        }
+        found.get_or_insert(candidate);
+    }
+    Ok(found)
+}
+
+async fn read_player(connection: &Connection, bus_name: String) -> zbus::Result<Player> {
+    let proxy: Proxy<'static> = zbus::proxy::Builder::new(connection)
+        .destination(bus_name)?
+        .path(OBJECT_PATH)?
+        .interface(PLAYER_INTERFACE)?
+        .cache_properties(CacheProperties::No)
+        .build().await?;
+
+    let status: String = proxy.get_property("PlaybackStatus").await?;
+    let metadata: HashMap<String, OwnedValue> = proxy.get_property("Metadata").await.unwrap_or_default();
+    // Not every player implements Position, e.g. for live streams.
+    let position_us: i64 = proxy.get_property("Position").await.unwrap_or(0);
+
+    let properties = PlayerProperties {
+        status,
+        title: metadata.get("xesam:title").and_then(|value| string(value)),
+        artists: metadata.get("xesam:artist").map(|value| strings(value)).unwrap_or_default(),
+        album: metadata.get("xesam:album").and_then(|value| string(value)),
+        genres: metadata.get("xesam:genre").map(|value| strings(value)).unwrap_or_default(),
+        length_us: metadata.get("mpris:length").and_then(|value| integer(value)),
+        use_count: metadata.get("xesam:useCount").and_then(|value| integer(value)).map(|count| count as i32),
+        position_us,
+    };
+    let track_id = metadata.get("mpris:trackid").and_then(|value| string(value));
+    Ok(Player { proxy, properties, track_id })
+}
+
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::Str(value) => Some(value.to_string()),
+        Value::ObjectPath(path) => Some(path.to_string()),
+        Value::Value(inner) => string(inner),
+        _ => None,
+    }
+}
+
+/// Reads a string list, accepting a single string from players that don't
+/// follow the spec.
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values.iter().filter_map(string).collect(),
+        other => string(other).into_iter().collect(),
+    }
+}
+
+/// Reads an integer of any width, as players disagree on the types.
+fn integer(value: &Value) -> Option<i64> {
+    match *value {
+        Value::I64(value) => Some(value),
+        Value::U64(value) => i64::try_from(value).ok(),
+        Value::I32(value) => Some(value.into()),
+        Value::U32(value) => Some(value.into()),
+        Value::F64(value) => Some(value as i64),
+        _ => None,
+    }
+}
+
+async fn execute_command(player: &Player, command: PlayerCommand) -> zbus::Result<()> {
+    let method = match command {
+        PlayerCommand::Play => "Play",
+        PlayerCommand::Pause => "Pause",
+        PlayerCommand::PlayPause => "PlayPause",
+        PlayerCommand::Next => "Next",
+        PlayerCommand::Previous => "Previous",
+        PlayerCommand::Seek(position) => {
+            let track_id = player.track_id.as_deref()
+                .ok_or_else(|| zbus::Error::Failure("the current track has no mpris:trackid".to_string()))?;
+            let track_id = ObjectPath::try_from(track_id)?;
+            player.proxy.call_method("SetPosition", &(track_id, (position * 1e6) as i64)).await?;
+            return Ok(());
+        }
+    };
+    player.proxy.call_method(method, &()).await?;
+    Ok(())
+}
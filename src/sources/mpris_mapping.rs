@@ -0,0 +1,119 @@
+use crate::{models::{PlayerState, TrackInfo}, sources::{SourceReport, RADIO_PLACEHOLDER}};
+
+/// The bus name prefix every MPRIS player registers under.
+pub const BUS_NAME_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+/// Properties read from a player's `org.mpris.MediaPlayer2.Player`
+/// interface; metadata fields are `None` or empty when the player omits them.
+#[derive(Debug, Default)]
+pub struct PlayerProperties {
+    pub status: String,
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub genres: Vec<String>,
+    pub length_us: Option<i64>,
+    pub use_count: Option<i32>,
+    pub position_us: i64,
+}
+
+impl PlayerProperties {
+    pub fn player_state(&self) -> PlayerState {
+        match self.status.as_str() {
+            "Playing" => PlayerState::Playing,
+            "Paused" => PlayerState::Paused,
+            "Stopped" => PlayerState::Stopped,
+            _ => PlayerState::Unavailable,
+        }
+    }
+}
+
+/// Whether a bus name belongs to an MPRIS player, and to the requested one
+/// if `player` is set (e.g. `spotify` matches `org.mpris.MediaPlayer2.spotify`
+/// and its `.instance123` variants).
+pub fn is_player(bus_name: &str, player: Option<&str>) -> bool {
+    let Some(rest) = bus_name.strip_prefix(BUS_NAME_PREFIX) else {
+        return false;
+    };
+    match player {
+        Some(player) => rest == player || rest.strip_prefix(player).is_some_and(|suffix| suffix.starts_with('.')),
+        None => true,
+    }
+}
+
+/// Maps a player's properties to a report, falling back to the radio
+/// placeholder when a playing stream has no title.
+pub fn map_properties(properties: &PlayerProperties, source: &str) -> SourceReport {
+    let state = properties.player_state();
+    if state == PlayerState::Stopped {
+        return SourceReport::without_track(source, state);
+    }
+
+    let or_unknown = |value: &Option<String>| value.clone().filter(|value| !value.is_empty()).unwrap_or_else(|| "Unknown".to_string());
+    let join_or_unknown = |values: &[String]| if values.is_empty() { "Unknown".to_string() } else { values.join(", ") };
+    let track_name = properties.title.clone().filter(|title| !title.is_empty()).unwrap_or_else(|| RADIO_PLACEHOLDER.to_string());
+
+    let track = TrackInfo {
+        track_name,
+        artist_name: join_or_unknown(&properties.artists),
+        progress: properties.position_us.max(0) as f64 / 1e6,
+        duration: properties.length_us.unwrap_or(0).max(0) as f32 / 1e6,
+        genre: join_or_unknown(&properties.genres),
+        favourited: false,
+        played_count: properties.use_count.unwrap_or(0),
+        album: or_unknown(&properties.album),
+        source: source.to_string(),
+        artwork_url: None,
+        timing: None,
+        state: None,
+    };
+    SourceReport { source: source.to_string(), state, track: Some(track) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_player_bus_names() {
+        assert!(is_player("org.mpris.MediaPlayer2.spotify", None));
+        assert!(is_player("org.mpris.MediaPlayer2.vlc.instance4242", Some("vlc")));
+        assert!(!is_player("org.mpris.MediaPlayer2.vlcx", Some("vlc")));
+        assert!(!is_player("org.freedesktop.Notifications", None));
+    }
+
+    #[test]
+    fn maps_metadata_in_seconds() {
+        let properties = PlayerProperties {
+            status: "Paused".to_string(),
+            title: Some("Teardrop".to_string()),
+            artists: vec!["Massive Attack".to_string(), "Elizabeth Fraser".to_string()],
+            length_us: Some(330_500_000),
+            position_us: 12_000_000,
+            ..Default::default()
+        };
+        let report = map_properties(&properties, "mpris");
+        assert_eq!(report.state, PlayerState::Paused);
+        let track = report.track.unwrap();
+        assert_eq!(track.artist_name, "Massive Attack, Elizabeth Fraser");
+        assert_eq!(track.duration, 330.5);
+        assert_eq!(track.progress, 12.0);
+        assert_eq!(track.album, "Unknown");
+    }
+
+    #[test]
+    fn streams_without_a_title_use_the_radio_placeholder() {
+        let properties = PlayerProperties { status: "Playing".to_string(), ..Default::default() };
+        let track = map_properties(&properties, "mpris").track.unwrap();
+        assert_eq!(track.track_name, RADIO_PLACEHOLDER);
+        assert_eq!(track.duration, 0.0);
+    }
+
+    #[test]
+    fn stopped_players_report_no_track() {
+        let properties = PlayerProperties { status: "Stopped".to_string(), title: Some("Roads".to_string()), ..Default::default() };
+        let report = map_properties(&properties, "mpris");
+        assert_eq!(report.state, PlayerState::Stopped);
+        assert!(report.track.is_none());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{sync::Arc, time::{Duration, Instant, SystemTime}};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+use tracing::{info, warn};
+
+use crate::{config::RelayConfig, models::{AppState, PlayerCommand, PlayerState, TrackInfo}, sources::SourceReport};
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// Pings double as the poll heartbeat, so they must stay below the readiness timeout.
+const PING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Turns a remote update into a report as of `now`; pause updates keep the
+/// last track. Remotes that predate player states only send pause markers.
+fn to_report(name: &str, mut track: TrackInfo, now: SystemTime) -> SourceReport {
+    if track.is_paused() {
+        return SourceReport::without_track(name, track.state.unwrap_or(PlayerState::Paused));
+    }
+    if track.source.is_empty() {
+        track.source = name.to_string();
+    }
+    // The progress was measured at the remote's `position_at`, which can be
+    // long before a snapshot or a replayed event reaches us.
+    track.progress = track.position_at(now);
+    track.timing = None;
+    SourceReport { source: name.to_string(), state: PlayerState::Playing, track: Some(track) }
+}
+
+/// Subscribes to a remote Rusty-Tapes instance's `/api/ws` and reports its
+/// updates as a local source, reconnecting with backoff.
+pub fn listen_for_relay(state: Arc<AppState>, name: String, config: RelayConfig, reports: mpsc::Sender<SourceReport>, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    info!("Starting relay source {} for {}", name, config.url);
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut reconnect_delay = MIN_RECONNECT_DELAY;
+
+        loop {
+            let connected_at = Instant::now();
+            let result = run_connection(&state, &name, &config, &client, &reports, &mut commands).await;
+            if reports.is_closed() {
+                break;
+            }
+            if let Err(e) = result {
+                warn!("Relay source {} disconnected: {}", name, e);
+                state.metrics.record_integration_error("relay", &e);
+            }
+            state.metrics.set_integration_connected("relay", false);
+            let _ = reports.send(SourceReport::without_track(&name, PlayerState::Unavailable)).await;
+
+            if connected_at.elapsed() > MAX_RECONNECT_DELAY {
+                reconnect_delay = MIN_RECONNECT_DELAY;
+            }
+            tokio::time::sleep(reconnect_delay).await;
+            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+}
+
+async fn run_connection(state: &AppState, name: &str, config: &RelayConfig, client: &reqwest::Client, reports: &mpsc::Sender<SourceReport>, commands: &mut mpsc::UnboundedReceiver<PlayerCommand>) -> Result<(), String> {
+    let base_url = config.url.trim_end_matches('/');
+    let ws_url = match base_url.split_once("://") {
+        Some(("http", rest)) => format!("ws://{}/api/ws", rest),
+        Some(("https", rest)) => format!("wss://{}/api/ws", rest),
+        _ => return Err(format!("Relay URL must start with http:// or https://, got {}", config.url)),
+    };
+    let ws_url = match &config.source {
+        Some(source) => format!("{}?source={}", ws_url, urlencoding::encode(source)),
+        None => ws_url,
+    };
+
+    let mut request = ws_url.as_str().into_client_request().map_err(|e| format!("Invalid relay URL: {}", e))?;
+    if let Some(token) = &config.token {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|e| format!("Invalid relay token: {}", e))?;
+        request.headers_mut().insert("Authorization", value);
+    }
+
+    let (socket, _) = tokio_tungstenite::connect_async(request).await
+        .map_err(|e| format!("Failed to connect to {}: {}", ws_url, e))?;
+    info!("Relay source {} connected to {}", name, ws_url);
+    state.metrics.set_integration_connected("relay", true);
+
+    // The WebSocket only carries changes, so start from the remote's current state.
+    if let Some(report) = fetch_snapshot(name, base_url, config, client).await? {
+        if reports.send(report).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let (mut sender, mut receiver) = socket.split();
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    let mut ping_sent: Option<Instant> = None;
+
+    loop {
+        tokio::select! {
+            message = receiver.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(e)) => return Err(format!("Relay connection error: {}", e)),
+                    None => return Err("Remote closed the connection".to_string()),
+                };
+                match message {
+                    Message::Text(text) => match serde_json::from_str::<TrackInfo>(&text) {
+                        Ok(track) => {
+                            if reports.send(to_report(name, track, SystemTime::now())).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Err(e) => warn!("Ignoring invalid relay message: {}", e),
+                    },
+                    Message::Pong(_) => {
+                        if let Some(sent) = ping_sent.take() {
+                            state.metrics.record_poll(name, sent.elapsed());
+                        }
+                    }
+                    Message::Close(_) => return Err("Remote closed the connection".to_string()),
+                    _ => {}
+                }
+            }
+            _ = ping_interval.tick() => {
+                ping_sent = Some(Instant::now());
+                sender.send(Message::Ping(Vec::new().into())).await.map_err(|e| format!("Failed to ping remote: {}", e))?;
+            }
+            command = commands.recv() => match command {
+                Some(command) => warn!("Relay source {} cannot forward command {:?}", name, command),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Fetches the remote's current state: the whole player's, or that of the
+/// configured source so that the relay matches its `?source=` stream.
+async fn fetch_snapshot(name: &str, base_url: &str, config: &RelayConfig, client: &reqwest::Client) -> Result<Option<SourceReport>, String> {
+    let fetch = |path: String| async move {
+        let request = client.get(format!("{}{}", base_url, path)).timeout(Duration::from_secs(10));
+        let request = match &config.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        request.send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to fetch {}: {}", path, e))?
+            .json::<serde_json::Value>().await
+            .map_err(|e| format!("Invalid response from {}: {}", path, e))
+    };
+
+    let (player_state, track) = match &config.source {
+        Some(source) => {
+            let status = fetch(format!("/api/sources/{}", urlencoding::encode(source))).await?;
+            (player_state(&status), parse_track(&status))
+        }
+        None => {
+            let status = fetch("/api/is_playing".to_string()).await?;
+            (player_state(&status), parse_track(&fetch("/api/last_track".to_string()).await?))
+        }
+    };
+
+    Ok(track.map(|track| {
+        let mut report = to_report(name, track, SystemTime::now());
+        if !player_state.is_playing() {
+            report.state = player_state;
+        }
+        report
+    }))
+}
+
+/// Reads the `state` of an `/api/is_playing` or `/api/sources/{name}`
+/// response, falling back to `is_playing` from remotes without states.
+fn player_state(status: &serde_json::Value) -> PlayerState {
+    status.get("state")
+        .and_then(|state| serde_json::from_value::<PlayerState>(state.clone()).ok())
+        .unwrap_or_else(|| match status.get("is_playing").or_else(|| status.get("playing")).and_then(|v| v.as_bool()) {
+            Some(true) => PlayerState::Playing,
+            _ => PlayerState::Paused,
+        })
+}
+
+fn parse_track(response: &serde_json::Value) -> Option<TrackInfo> {
+    response.get("track").and_then(|track| serde_json::from_value::<TrackInfo>(track.clone()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_source_snapshots() {
+        let track = TrackInfo::test("Roads", "Portishead", 305.0, 62.0);
+        let status = serde_json::json!({ "name": "mpd", "state": "paused", "playing": false, "track": track, "active": true });
+        assert_eq!(player_state(&status), PlayerState::Paused);
+        assert_eq!(parse_track(&status).map(|track| track.track_name), Some("Roads".to_string()));
+
+        let stopped = serde_json::json!({ "name": "mpd", "state": "not_running", "playing": false, "track": null });
+        assert_eq!(player_state(&stopped), PlayerState::NotRunning);
+        assert!(parse_track(&stopped).is_none());
+    }
+
+    #[test]
+    fn falls_back_to_is_playing_from_older_remotes() {
+        assert_eq!(player_state(&serde_json::json!({ "is_playing": true })), PlayerState::Playing);
+        assert_eq!(player_state(&serde_json::json!({ "is_playing": false })), PlayerState::Paused);
+    }
+
+    #[test]
+    fn pause_markers_keep_the_remote_state() {
+        let paused = TrackInfo { duration: -1.0, state: Some(PlayerState::Stopped), ..TrackInfo::test("Roads", "Portishead", 305.0, 0.0) };
+        let report = to_report("studio", paused, SystemTime::now());
+        assert_eq!(report.state, PlayerState::Stopped);
+        assert!(report.track.is_none());
+
+        let report = to_report("studio", TrackInfo { source: String::new(), ..TrackInfo::test("Roads", "Portishead", 305.0, 0.0) }, SystemTime::now());
+        assert_eq!(report.track.unwrap().source, "studio");
+    }
+
+    #[test]
+    fn old_snapshots_report_the_position_for_now() {
+        let measured = SystemTime::now() - Duration::from_secs(8);
+        let track = TrackInfo::test("Roads", "Portishead", 305.0, 62.0).with_timing(measured, 1.0);
+        let status = serde_json::json!({ "name": "mpd", "state": "playing", "playing": true, "track": track });
+
+        let report = to_report("studio", parse_track(&status).unwrap(), measured + Duration::from_secs(8));
+        let track = report.track.unwrap();
+        assert_eq!(track.progress, 70.0);
+        assert!(track.timing.is_none());
+
+        // Near the end, the position stops at the track's length.
+        let ending = TrackInfo::test("Roads", "Portishead", 305.0, 300.0).with_timing(measured, 1.0);
+        assert_eq!(to_report("studio", ending, measured + Duration::from_secs(8)).track.unwrap().progress, 305.0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use crate::{config::PollingConfig, sources::SourceReport};
+
+/// How long after a track's expected end to poll, so that the next track has
+/// usually started by then.
+const END_OF_TRACK_MARGIN: Duration = Duration::from_millis(200);
+
+/// Decides when a polled source should next ask its player: regularly while
+/// playing, sooner when the track is about to end, and rarely while idle.
+#[derive(Clone, Debug)]
+pub struct PollScheduler {
+    min_interval: Duration,
+    playing_interval: Duration,
+    max_interval: Duration,
+}
+
+impl PollScheduler {
+    pub fn new(config: &PollingConfig) -> Self {
+        let min_interval = Duration::from_millis(config.min_interval_ms);
+        let playing_interval = Duration::from_millis(config.playing_interval_ms).max(min_interval);
+        let max_interval = Duration::from_millis(config.max_interval_ms).max(playing_interval);
+        PollScheduler { min_interval, playing_interval, max_interval }
+    }
+
+    /// Overrides the interval used while playing, e.g. for rate-limited APIs,
+    /// raising the idle interval to match if needed.
+    pub fn with_playing_interval(mut self, interval: Duration) -> Self {
+        self.playing_interval = interval.max(self.min_interval);
+        self.max_interval = self.max_interval.max(self.playing_interval);
+        self
+    }
+
+    /// The delay before polling again after `report`.
+    pub fn next_delay(&self, report: &SourceReport) -> Duration {
+        let Some(track) = report.track.as_ref().filter(|_| report.state.is_playing()) else {
+            return self.max_interval;
+        };
+        if track.duration <= 0.0 {
+            return self.playing_interval;
+        }
+        let remaining = Duration::from_secs_f64((track.duration as f64 - track.progress).max(0.0));
+        (remaining + END_OF_TRACK_MARGIN).clamp(self.min_interval, self.playing_interval)
+    }
+
+    /// The delay after a failed poll, backing off towards the idle interval.
+    pub fn backoff(&self, previous: Duration) -> Duration {
+        (previous * 2).clamp(self.playing_interval, self.max_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::models::{PlayerState, TrackInfo};
+
+    fn scheduler() -> PollScheduler {
+        PollScheduler::new(&PollingConfig { min_interval_ms: 250, playing_interval_ms: 1000, max_interval_ms: 5000 })
+    }
+
+    fn report(state: PlayerState, duration: f32, progress: f64) -> SourceReport {
+        SourceReport { source: "test".to_string(), state, track: Some(TrackInfo::test("Roads", "Portishead", duration, progress)) }
+    }
+
+    #[test]
+    fn polls_sooner_near_the_end_of_a_track() {
+        let scheduler = scheduler();
+        assert_eq!(scheduler.next_delay(&report(PlayerState::Playing, 300.0, 10.0)), Duration::from_millis(1000));
+        assert_eq!(scheduler.next_delay(&report(PlayerState::Playing, 300.0, 299.5)), Duration::from_millis(700));
+        // Past the end, never faster than the minimum.
+        assert_eq!(scheduler.next_delay(&report(PlayerState::Playing, 300.0, 310.0)), Duration::from_millis(250));
+        // Streams without a duration keep the playing interval.
+        assert_eq!(scheduler.next_delay(&report(PlayerState::Playing, 0.0, 42.0)), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn polls_rarely_while_idle() {
+        let scheduler = scheduler();
+        assert_eq!(scheduler.next_delay(&report(PlayerState::Paused, 300.0, 10.0)), Duration::from_millis(5000));
+        assert_eq!(scheduler.next_delay(&SourceReport::without_track("test", PlayerState::NotRunning)), Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn backoff_stays_within_bounds() {
+        let scheduler = scheduler();
+        assert_eq!(scheduler.backoff(Duration::ZERO), Duration::from_millis(1000));
+        assert_eq!(scheduler.backoff(Duration::from_millis(1500)), Duration::from_millis(3000));
+        assert_eq!(scheduler.backoff(Duration::from_millis(4000)), Duration::from_millis(5000));
+        assert_eq!(scheduler.backoff(Duration::MAX / 4), Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn intervals_are_kept_in_order() {
+        let inverted = PollScheduler::new(&PollingConfig { min_interval_ms: 2000, playing_interval_ms: 1000, max_interval_ms: 500 });
+        assert_eq!(inverted.next_delay(&report(PlayerState::Playing, 300.0, 10.0)), Duration::from_millis(2000));
+        assert_eq!(inverted.backoff(Duration::ZERO), Duration::from_millis(2000));
+
+        let rate_limited = scheduler().with_playing_interval(Duration::from_secs(10));
+        assert_eq!(rate_limited.next_delay(&report(PlayerState::Playing, 300.0, 10.0)), Duration::from_secs(10));
+        assert_eq!(rate_limited.next_delay(&report(PlayerState::Paused, 300.0, 10.0)), Duration::from_secs(10));
+    }
+}
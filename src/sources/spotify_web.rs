@@ -0,0 +1,509 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use axum::{extract::Query, http::StatusCode, response::{IntoResponse, Redirect, Response}, routing::get, Extension, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{distr::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{config::{PollingConfig, SpotifyConfig}, models::{AppState, PlayerCommand, PlayerState, TrackInfo}, sources::{schedule::PollScheduler, SourceReport}, utils};
+
+const SCOPES: &str = "user-read-currently-playing user-read-playback-state user-modify-playback-state";
+/// Tokens are refreshed this long before they expire.
+const REFRESH_MARGIN_SECS: u64 = 60;
+const MAX_PENDING_LOGINS: usize = 16;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct Tokens {
+    access_token: String,
+    refresh_token: String,
+    /// Unix timestamp in seconds
+    expires_at: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+    refresh_token: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+enum SpotifyError {
+    NotLoggedIn,
+    RateLimited(Duration),
+    Request(String),
+}
+
+impl SpotifyError {
+    fn message(&self) -> String {
+        match self {
+            SpotifyError::NotLoggedIn => "not logged in to Spotify".to_string(),
+            SpotifyError::RateLimited(retry_after) => format!("rate limited for {}s", retry_after.as_secs()),
+            SpotifyError::Request(message) => message.clone(),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SpotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        SpotifyError::Request(e.to_string())
+    }
+}
+
+/// A client for Spotify's Web API that logs in with OAuth PKCE and keeps
+/// its tokens in `token_file`.
+pub struct SpotifyWeb {
+    config: SpotifyConfig,
+    client: reqwest::Client,
+    tokens: tokio::sync::Mutex<Option<Tokens>>,
+    /// Held while refreshing, so that concurrent callers share one refresh
+    refresh_lock: tokio::sync::Mutex<()>,
+    /// Code verifiers of logins in progress, keyed by their `state` parameter
+    pending_logins: Mutex<HashMap<String, String>>,
+}
+
+impl SpotifyWeb {
+    pub fn new(config: SpotifyConfig) -> Arc<Self> {
+        let tokens = std::fs::read_to_string(&config.token_file).ok()
+            .and_then(|contents| serde_json::from_str::<Tokens>(&contents).ok());
+        if tokens.is_none() {
+            info!("No Spotify token found at {}; log in via /api/spotify/login", config.token_file.display());
+        }
+
+        Arc::new(SpotifyWeb {
+            config,
+            client: reqwest::Client::new(),
+            tokens: tokio::sync::Mutex::new(tokens),
+            refresh_lock: tokio::sync::Mutex::new(()),
+            pending_logins: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn authorize_url(&self) -> String {
+        let verifier = random_string(64);
+        let login_state = random_string(16);
+        let challenge = code_challenge(&verifier);
+
+        let mut pending = self.pending_logins.lock().unwrap();
+        if pending.len() >= MAX_PENDING_LOGINS {
+            pending.clear();
+        }
+        pending.insert(login_state.clone(), verifier);
+
+        format!("{}/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&code_challenge_method=S256&code_challenge={}&state={}",
+            self.config.accounts_url.trim_end_matches('/'),
+            urlencoding::encode(&self.config.client_id),
+            urlencoding::encode(&self.config.redirect_uri),
+            urlencoding::encode(SCOPES),
+            challenge,
+            login_state,
+        )
+    }
+
+    async fn exchange_code(&self, code: &str, login_state: &str) -> Result<(), String> {
+        let verifier = self.pending_logins.lock().unwrap().remove(login_state)
+            .ok_or_else(|| "Unknown or expired login state".to_string())?;
+
+        let response = self.request_token(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &verifier),
+        ]).await?;
+
+        let refresh_token = response.refresh_token.clone()
+            .ok_or_else(|| "Spotify did not return a refresh token".to_string())?;
+        self.store_tokens(&response, refresh_token).await;
+        Ok(())
+    }
+
+    async fn request_token(&self, form: &[(&str, &str)]) -> Result<TokenResponse, String> {
+        let response = self.client.post(format!("{}/api/token", self.config.accounts_url.trim_end_matches('/')))
+            .form(form)
+            .send().await
+            .map_err(|e| format!("Token request failed: {}", e))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Token request failed with {}: {}", status, body));
+        }
+        response.json::<TokenResponse>().await.map_err(|e| format!("Invalid token response: {}", e))
+    }
+
+    async fn store_tokens(&self, response: &TokenResponse, refresh_token: String) -> Tokens {
+        let tokens = Tokens {
+            access_token: response.access_token.clone(),
+            refresh_token,
+            expires_at: unix_timestamp() + response.expires_in,
+        };
+
+        if let Some(parent) = self.config.token_file.parent() {
+            let _ = tokio::fs::create_dir_all(parent).await;
+        }
+        let contents = serde_json::to_vec_pretty(&tokens).unwrap_or_default();
+        if let Err(e) = utils::write_private_atomically(&self.config.token_file, &contents).await {
+            warn!("Failed to save Spotify token to {}: {}", self.config.token_file.display(), e);
+        }
+
+        *self.tokens.lock().await = Some(tokens.clone());
+        tokens
+    }
+
+    /// Returns a valid access token, refreshing it first if it is about to expire.
+    async fn access_token(&self) -> Result<String, SpotifyError> {
+        let tokens = self.current_tokens().await?;
+        if tokens.expires_at > unix_timestamp() + REFRESH_MARGIN_SECS {
+            return Ok(tokens.access_token);
+        }
+
+        // Another caller may have refreshed the token while we waited.
+        let _refreshing = self.refresh_lock.lock().await;
+        let tokens = self.current_tokens().await?;
+        if tokens.expires_at > unix_timestamp() + REFRESH_MARGIN_SECS {
+            return Ok(tokens.access_token);
+        }
+
+        info!("Refreshing Spotify access token");
+        let response = self.request_token(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &tokens.refresh_token),
+            ("client_id", &self.config.client_id),
+        ]).await.map_err(SpotifyError::Request)?;
+
+        // Spotify may rotate the refresh token; keep the old one otherwise.
+        let refresh_token = response.refresh_token.clone().unwrap_or(tokens.refresh_token);
+        Ok(self.store_tokens(&response, refresh_token).await.access_token)
+    }
+
+    async fn current_tokens(&self) -> Result<Tokens, SpotifyError> {
+        self.tokens.lock().await.clone().ok_or(SpotifyError::NotLoggedIn)
+    }
+
+    async fn expire_access_token(&self) {
+        if let Some(tokens) = self.tokens.lock().await.as_mut() {
+            tokens.expires_at = 0;
+        }
+    }
+
+    fn api_url(&self, path: &str) -> String {
+        format!("{}{}", self.config.api_url.trim_end_matches('/'), path)
+    }
+
+    /// Returns Spotify's player state and the current item, if any.
+    async fn currently_playing(&self, source: &str) -> Result<(PlayerState, Option<TrackInfo>), SpotifyError> {
+        let access_token = self.access_token().await?;
+        let response = self.client.get(self.api_url("/v1/me/player/currently-playing?additional_types=track,episode"))
+            .bearer_auth(access_token)
+            .send().await?;
+
+        match response.status() {
+            StatusCode::NO_CONTENT => return Ok((PlayerState::Stopped, None)),
+            StatusCode::UNAUTHORIZED => {
+                self.expire_access_token().await;
+                return Err(SpotifyError::Request("access token was rejected".to_string()));
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = response.headers().get("retry-after")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(30);
+                return Err(SpotifyError::RateLimited(Duration::from_secs(retry_after)));
+            }
+            status if !status.is_success() => return Err(SpotifyError::Request(format!("currently playing request failed with {}", status))),
+            _ => {}
+        }
+
+        let json = response.json::<serde_json::Value>().await?;
+        let is_playing = json.get("is_playing").and_then(|v| v.as_bool()).unwrap_or(false);
+        let progress_ms = json.get("progress_ms").and_then(|v| v.as_f64()).unwrap_or(0.0);
+        let track = json.get("item").filter(|item| !item.is_null()).map(|item| map_item(item, progress_ms, source));
+        let player_state = match (is_playing, &track) {
+            (true, _) => PlayerState::Playing,
+            (false, Some(_)) => PlayerState::Paused,
+            (false, None) => PlayerState::Stopped,
+        };
+        Ok((player_state, track))
+    }
+
+    async fn execute_command(&self, command: PlayerCommand) -> Result<(), SpotifyError> {
+        let access_token = self.access_token().await?;
+        let request = match command {
+            PlayerCommand::Play => self.client.put(self.api_url("/v1/me/player/play")),
+            PlayerCommand::Pause => self.client.put(self.api_url("/v1/me/player/pause")),
+            PlayerCommand::PlayPause => {
+                let (player_state, _) = self.currently_playing("").await?;
+                let path = if player_state.is_playing() { "/v1/me/player/pause" } else { "/v1/me/player/play" };
+                self.client.put(self.api_url(path))
+            }
+            PlayerCommand::Next => self.client.post(self.api_url("/v1/me/player/next")),
+            PlayerCommand::Previous => self.client.post(self.api_url("/v1/me/player/previous")),
+            PlayerCommand::Seek(position) => self.client.put(self.api_url(&format!("/v1/me/player/seek?position_ms={}", (position * 1000.0) as u64))),
+        };
+
+        let response = request.bearer_auth(access_token).header("Content-Length", "0").send().await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(SpotifyError::Request(format!("player command failed with {}", response.status())))
+        }
+    }
+}
+
+/// The S256 PKCE challenge for a code verifier (RFC 7636).
+fn code_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Maps a `currently-playing` item, either a track or a podcast episode.
+fn map_item(item: &serde_json::Value, progress_ms: f64, source: &str) -> TrackInfo {
+    let text = |value: Option<&serde_json::Value>| value.and_then(|v| v.as_str()).unwrap_or("Unknown").to_string();
+
+    let (artist_name, album, images) = match item.get("show") {
+        Some(show) => (text(show.get("name")), text(show.get("name")), item.get("images")),
+        None => {
+            let artists = item.get("artists")
+                .and_then(|artists| artists.as_array())
+                .map(|artists| artists.iter().filter_map(|artist| artist.get("name")?.as_str()).collect::<Vec<_>>().join(", "))
+                .filter(|artists| !artists.is_empty())
+                .unwrap_or_else(|| "Unknown".to_string());
+            let album = item.get("album");
+            (artists, text(album.and_then(|album| album.get("name"))), album.and_then(|album| album.get("images")))
+        }
+    };
+
+    // Images are listed widest first.
+    let artwork_url = images
+        .and_then(|images| images.as_array())
+        .and_then(|images| images.first())
+        .and_then(|image| image.get("url")?.as_str())
+        .map(str::to_string);
+
+    TrackInfo {
+        track_name: text(item.get("name")),
+        artist_name,
+        progress: progress_ms / 1000.0,
+        duration: (item.get("duration_ms").and_then(|v| v.as_f64()).unwrap_or(0.0) / 1000.0) as f32,
+        genre: "Unknown".to_string(),
+        favourited: false,
+        played_count: 0,
+        album,
+        source: source.to_string(),
+        artwork_url,
+        timing: None,
+        state: None,
+    }
+}
+
+pub fn listen_for_spotify(state: Arc<AppState>, name: String, spotify: Arc<SpotifyWeb>, polling: &PollingConfig, reports: mpsc::Sender<SourceReport>, mut commands: mpsc::UnboundedReceiver<PlayerCommand>) {
+    info!("Starting Spotify Web API source {}", name);
+
+    // The configured interval is kept while playing to stay within rate limits.
+    let scheduler = PollScheduler::new(polling)
+        .with_playing_interval(Duration::from_secs(spotify.config.poll_interval_secs.max(1)));
+    tokio::spawn(async move {
+        let mut warned_logged_out = false;
+        let mut delay = Duration::ZERO;
+
+        loop {
+            let poll_started = Instant::now();
+            let report = match spotify.currently_playing(&name).await {
+                Ok(result) => {
+                    state.metrics.record_poll(&name, poll_started.elapsed());
+                    state.metrics.set_integration_connected("spotify_web", true);
+                    warned_logged_out = false;
+                    let (player_state, track) = result;
+                    Some(SourceReport { source: name.clone(), state: player_state, track })
+                }
+                Err(SpotifyError::NotLoggedIn) => {
+                    if !warned_logged_out {
+                        warn!("Spotify source {} is not logged in; visit /api/spotify/login", name);
+                        warned_logged_out = true;
+                    }
+                    state.metrics.set_integration_connected("spotify_web", false);
+                    Some(SourceReport::without_track(&name, PlayerState::Unavailable))
+                }
+                Err(e) => {
+                    warn!("Spotify source {} failed to poll: {}", name, e.message());
+                    state.metrics.record_integration_error("spotify_web", e.message());
+                    delay = scheduler.backoff(delay);
+                    if let SpotifyError::RateLimited(retry_after) = e {
+                        delay = delay.max(retry_after);
+                    }
+                    // Keep the last known state through transient failures.
+                    None
+                }
+            };
+
+            if let Some(report) = report {
+                delay = scheduler.next_delay(&report);
+                state.metrics.record_poll_interval(&name, delay);
+                if reports.send(report).await.is_err() {
+                    break;
+                }
+            }
+
+            tokio::select! {
+                command = commands.recv() => {
+                    let Some(command) = command else { break };
+                    match spotify.execute_command(command).await {
+                        Ok(()) => info!("Executed player command {:?} on {}", command, name),
+                        Err(e) => {
+                            warn!("Spotify rejected command {:?}: {}", command, e.message());
+                            state.metrics.record_integration_error("spotify_web", e.message());
+                        }
+                    }
+                }
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    });
+}
+
+/// The PKCE login endpoints under `/api/spotify`.
+pub fn routes(spotify: Arc<SpotifyWeb>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/spotify/login", get(login))
+        .route("/api/spotify/callback", get(callback))
+        .layer(Extension(spotify))
+}
+
+async fn login(Extension(spotify): Extension<Arc<SpotifyWeb>>) -> Redirect {
+    Redirect::to(&spotify.authorize_url())
+}
+
+async fn callback(Extension(spotify): Extension<Arc<SpotifyWeb>>, Query(params): Query<CallbackParams>) -> Response {
+    if let Some(error) = params.error {
+        warn!("Spotify login was denied: {}", error);
+        return (StatusCode::BAD_REQUEST, format!("Spotify login failed: {}", error)).into_response();
+    }
+    let (Some(code), Some(login_state)) = (params.code, params.state) else {
+        return (StatusCode::BAD_REQUEST, "Missing code or state").into_response();
+    };
+
+    match spotify.exchange_code(&code, &login_state).await {
+        Ok(()) => {
+            info!("Logged in to Spotify");
+            (StatusCode::OK, "Logged in to Spotify. You can close this window.").into_response()
+        }
+        Err(e) => {
+            warn!("Spotify login failed: {}", e);
+            (StatusCode::BAD_GATEWAY, format!("Spotify login failed: {}", e)).into_response()
+        }
+    }
+}
+
+fn random_string(length: usize) -> String {
+    rand::rng().sample_iter(&Alphanumeric).take(length).map(char::from).collect()
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{routing::post, Json};
+
+    fn spotify(accounts_url: &str, token_file: &std::path::Path) -> Arc<SpotifyWeb> {
+        let config = toml::from_str(&format!("client_id = \"rusty\"\naccounts_url = \"{}\"\ntoken_file = {:?}", accounts_url, token_file))
+            .expect("valid Spotify config");
+        SpotifyWeb::new(config)
+    }
+
+    #[test]
+    fn code_challenge_matches_rfc_7636() {
+        assert_eq!(code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"), "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+    }
+
+    #[test]
+    fn authorize_url_keeps_the_verifier_for_its_challenge() {
+        let spotify = spotify("https://accounts.example", &std::env::temp_dir().join("rusty-tapes-missing-token.json"));
+        let url = spotify.authorize_url();
+
+        let (login_state, verifier) = spotify.pending_logins.lock().unwrap().iter()
+            .map(|(login_state, verifier)| (login_state.clone(), verifier.clone()))
+            .next().unwrap();
+        // RFC 7636 requires 43 to 128 unreserved characters.
+        assert!((43..=128).contains(&verifier.len()));
+        assert!(url.starts_with("https://accounts.example/authorize?"));
+        assert!(url.contains(&format!("&code_challenge={}&", code_challenge(&verifier))));
+        assert!(url.ends_with(&format!("&state={}", login_state)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_refresh() {
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let counter = refreshes.clone();
+        let app = Router::new().route("/api/token", post(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Json(serde_json::json!({ "access_token": "fresh", "expires_in": 3600 }))
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let accounts_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = std::env::temp_dir().join(format!("rusty-tapes-spotify-{}", std::process::id()));
+        let token_file = dir.join("token.json");
+        let spotify = spotify(&accounts_url, &token_file);
+        *spotify.tokens.lock().await = Some(Tokens { access_token: "stale".to_string(), refresh_token: "refresh".to_string(), expires_at: 0 });
+
+        let (first, second) = tokio::join!(spotify.access_token(), spotify.access_token());
+        assert_eq!(first.ok().as_deref(), Some("fresh"));
+        assert_eq!(second.ok().as_deref(), Some("fresh"));
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&token_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn maps_tracks_in_seconds() {
+        let item = serde_json::json!({
+            "name": "Teardrop",
+            "duration_ms": 330_000,
+            "artists": [{ "name": "Massive Attack" }, { "name": "Elizabeth Fraser" }],
+            "album": { "name": "Mezzanine", "images": [{ "url": "https://i.example/640" }, { "url": "https://i.example/300" }] },
+        });
+        let track = map_item(&item, 12_500.0, "spotify_web");
+        assert_eq!(track.artist_name, "Massive Attack, Elizabeth Fraser");
+        assert_eq!(track.album, "Mezzanine");
+        assert_eq!(track.duration, 330.0);
+        assert_eq!(track.progress, 12.5);
+        assert_eq!(track.artwork_url.as_deref(), Some("https://i.example/640"));
+    }
+
+    #[test]
+    fn maps_episodes_to_their_show() {
+        let item = serde_json::json!({
+            "name": "Episode 1",
+            "duration_ms": 1_800_000,
+            "show": { "name": "The Podcast" },
+            "images": [{ "url": "https://i.example/episode" }],
+        });
+        let track = map_item(&item, 0.0, "spotify_web");
+        assert_eq!(track.track_name, "Episode 1");
+        assert_eq!(track.artist_name, "The Podcast");
+        assert_eq!(track.album, "The Podcast");
+        assert_eq!(track.artwork_url.as_deref(), Some("https://i.example/episode"));
+    }
+}
@@ -0,0 +1,42 @@
+use std::{convert::Infallible, sync::{atomic::Ordering, Arc}};
+
+use axum::{extract::State, http::HeaderMap, response::sse::{Event, KeepAlive, Sse}};
+use futures_util::stream::{self, Stream, StreamExt};
+use tracing::info;
+
+use crate::{fanout::Frame, models::AppState};
+
+/// Decrements the connection count when an SSE stream is dropped.
+struct ConnectionGuard(Arc<AppState>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let final_count = self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+        info!("SSE client disconnected. Total: {}", final_count - 1);
+    }
+}
+
+pub async fn events_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_event_id = headers.get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+
+    let connection_count = state.active_connections.fetch_add(1, Ordering::Relaxed);
+    info!("New SSE client connection (Last-Event-ID: {:?}). Total: {}", last_event_id, connection_count + 1);
+
+    let (backlog, frames) = state.subscribe_from(last_event_id);
+    let guard = ConnectionGuard(state);
+
+    // The hub closes `frames` if this client falls too far behind; it can
+    // then reconnect and resume from its Last-Event-ID.
+    let live = stream::unfold((frames, guard), |(mut frames, guard)| async move {
+        frames.recv().await.map(|frame| (frame, (frames, guard)))
+    });
+
+    let events = stream::iter(backlog).chain(live).map(|frame| Ok(to_sse_event(&frame)));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+fn to_sse_event(frame: &Frame) -> Event {
+    Event::default().id(frame.id.to_string()).data(frame.json.as_str())
+}
@@ -0,0 +1,148 @@
+use crate::{models::{TrackEvent, TrackInfo}, playback::PlayOutcome};
+
+/// Renders a user template such as `{artist} — {track} ({album})` for `track`.
+///
+/// Unknown placeholders are left untouched, and `{{`/`}}` produce literal braces.
+pub fn render(template: &str, track: &TrackInfo) -> String {
+    render_with(template, track, None, |value| value)
+}
+
+/// Like [`render`], but also fills in `{previous_play}` and
+/// `{previous_play_position}` from how the event ended the previous play.
+pub fn render_event(template: &str, event: &TrackEvent) -> String {
+    render_with(template, &event.track, event.ended, |value| value)
+}
+
+/// Like [`render_event`], but escapes substituted values so they can be
+/// embedded in JSON string literals.
+pub fn render_event_json(template: &str, event: &TrackEvent) -> String {
+    render_with(template, &event.track, event.ended, |value| {
+        // Drop only the surrounding quotes; a value may end in an escaped one.
+        let quoted = serde_json::to_string(&value).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    })
+}
+
+/// Like [`render`], but percent-encodes substituted values so they can be
+/// embedded in URLs, e.g. `https://www.last.fm/music/{artist}/_/{track}`.
+pub fn render_url(template: &str, track: &TrackInfo) -> String {
+    render_with(template, track, None, |value| urlencoding::encode(&value).into_owned())
+}
+
+fn render_with(template: &str, track: &TrackInfo, ended: Option<PlayOutcome>, escape: impl Fn(String) -> String) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(index) = rest.find(['{', '}']) {
+        out.push_str(&rest[..index]);
+        let tail = &rest[index..];
+
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+
+        match tail.strip_prefix('{').and_then(|t| t.split_once('}')) {
+            Some((name, after)) => {
+                match placeholder(name, track, ended) {
+                    Some(value) => out.push_str(&escape(value)),
+                    None => {
+                        out.push('{');
+                        out.push_str(name);
+                        out.push('}');
+                    }
+                }
+                rest = after;
+            }
+            None => {
+                out.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn placeholder(name: &str, track: &TrackInfo, ended: Option<PlayOutcome>) -> Option<String> {
+    let value = match name {
+        "track" => track.track_name.clone(),
+        "artist" => track.artist_name.clone(),
+        "album" => track.album.clone(),
+        "genre" => track.genre.clone(),
+        "duration" => format_time(track.duration as f64),
+        "progress" => format_time(track.progress),
+        "played_count" => track.played_count.to_string(),
+        "favourited" => if track.favourited { "♥".to_string() } else { String::new() },
+        "source" => crate::sources::display_name(&track.source).to_string(),
+        "state" => match track.state {
+            Some(state) => state.as_str().to_string(),
+            None if track.is_paused() => "paused".to_string(),
+            None => "playing".to_string(),
+        },
+        "previous_play" => match ended {
+            Some(PlayOutcome::Completed) => "completed".to_string(),
+            Some(PlayOutcome::Skipped { .. }) => "skipped".to_string(),
+            Some(PlayOutcome::Interrupted { .. }) => "interrupted".to_string(),
+            None => String::new(),
+        },
+        "previous_play_position" => match ended {
+            Some(PlayOutcome::Skipped { position } | PlayOutcome::Interrupted { position }) => format_time(position),
+            _ => String::new(),
+        },
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Formats seconds as `m:ss`, or an empty string for unknown durations.
+pub fn format_time(seconds: f64) -> String {
+    if seconds <= 0.0 {
+        return String::new();
+    }
+    let seconds = seconds as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn event(track: TrackInfo, ended: Option<PlayOutcome>) -> TrackEvent {
+        TrackEvent { id: 1, seq: 1, track, kind: crate::models::TrackEventKind::TrackChanged, privacy_matches: Arc::from([]), api_json: None, ended }
+    }
+
+    #[test]
+    fn renders_placeholders_and_escaped_braces() {
+        let track = TrackInfo::test("Roads", "Portishead", 305.0, 62.0);
+        assert_eq!(render("{artist} — {track} [{progress}/{duration}]", &track), "Portishead — Roads [1:02/5:05]");
+        assert_eq!(render("{{track}} {unknown}", &track), "{track} {unknown}");
+    }
+
+    #[test]
+    fn json_escapes_values_ending_in_a_quote() {
+        let track = TrackInfo::test("Say \"Hello\"", "A\\B", 200.0, 0.0);
+        let body = render_event_json(r#"{{"text": "{track} by {artist}"}}"#, &event(track, None));
+        let parsed: serde_json::Value = serde_json::from_str(&body).expect("valid JSON");
+        assert_eq!(parsed["text"], "Say \"Hello\" by A\\B");
+    }
+
+    #[test]
+    fn url_encodes_values() {
+        let track = TrackInfo::test("Teardrop", "Massive Attack", 330.0, 0.0);
+        assert_eq!(render_url("https://www.last.fm/music/{artist}/_/{track}", &track), "https://www.last.fm/music/Massive%20Attack/_/Teardrop");
+    }
+
+    #[test]
+    fn renders_how_the_event_ended_the_previous_play() {
+        let track = TrackInfo::test("Teardrop", "Massive Attack", 330.0, 0.0);
+        let template = "{previous_play} at {previous_play_position}";
+        assert_eq!(render_event(template, &event(track.clone(), Some(PlayOutcome::Skipped { position: 95.5 }))), "skipped at 1:35");
+        assert_eq!(render_event(template, &event(track.clone(), Some(PlayOutcome::Completed))), "completed at ");
+        assert_eq!(render_event(template, &event(track.clone(), None)), " at ");
+        assert_eq!(render(template, &track), " at ");
+    }
+}
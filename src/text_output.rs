@@ -0,0 +1,167 @@
+use std::{path::{Path, PathBuf}, sync::{atomic::Ordering, Arc}};
+
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{config::PrivacySink, models::{AppState, Args, TrackInfo}, templates, utils};
+
+const DEFAULT_TEXT_FILES: [(&str, &str); 4] = [
+    ("nowplaying.txt", "{artist} — {track}"),
+    ("track.txt", "{track}"),
+    ("artist.txt", "{artist}"),
+    ("album.txt", "{album}"),
+];
+
+#[derive(Clone, Debug)]
+pub struct TextOutputConfig {
+    pub output_dir: PathBuf,
+    pub files: Vec<(String, String)>,
+    pub cover_file: Option<String>,
+    pub clear_when_paused: bool,
+}
+
+impl TextOutputConfig {
+    pub fn from_args(output_dir: &Path, args: &Args) -> Self {
+        let files = if args.text_files.is_empty() {
+            DEFAULT_TEXT_FILES.iter().map(|(file, template)| (file.to_string(), template.to_string())).collect()
+        } else {
+            args.text_files.clone()
+        };
+
+        TextOutputConfig {
+            output_dir: output_dir.to_path_buf(),
+            files,
+            cover_file: args.text_cover_file.clone(),
+            clear_when_paused: args.text_clear_when_paused,
+        }
+    }
+}
+
+pub fn text_output_task(state: Arc<AppState>, config: TextOutputConfig) {
+    info!("Starting text output task, writing to {}", config.output_dir.display());
+
+    tokio::spawn(async move {
+        if let Err(e) = tokio::fs::create_dir_all(&config.output_dir).await {
+            warn!("Failed to create text output directory {}: {}", config.output_dir.display(), e);
+            state.metrics.record_integration_error("text_output", e);
+            return;
+        }
+        state.metrics.set_integration_connected("text_output", true);
+
+        let mut last_cover: Option<String> = None;
+        let mut receiver = state.client_sender.subscribe();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => state.privacy.filter_event(PrivacySink::TextOutput, &event),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Text output task lagged behind, dropped {} messages", skipped);
+                    state.metrics.broadcast_lag_drops.fetch_add(skipped, Ordering::Relaxed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            // Hidden tracks and stopped players clear the files rather than
+            // leaving the previous track up.
+            let event = event.filter(|event| !event.track.is_stopped());
+            let shown = event.as_ref().filter(|event| !(config.clear_when_paused && event.track.is_paused()));
+            for (file, template) in &config.files {
+                let contents = shown.map(|event| templates::render_event(template, event)).unwrap_or_default();
+                if let Err(e) = utils::write_atomically(&config.output_dir.join(file), contents.as_bytes()).await {
+                    warn!("Failed to write text output file {}: {}", file, e);
+                    state.metrics.record_integration_error("text_output", e);
+                }
+            }
+
+            if let Some(cover_file) = &config.cover_file {
+                let track = shown.map(|event| &event.track);
+                write_cover(&state, &config.output_dir.join(cover_file), track, &mut last_cover).await;
+            }
+        }
+    });
+}
+
+/// Writes the shown track's artwork to `path`, or removes the file when no
+/// track is shown or it has no artwork, so a previous cover never lingers.
+async fn write_cover(state: &AppState, path: &Path, track: Option<&TrackInfo>, last_cover: &mut Option<String>) {
+    let image_url = match track {
+        Some(track) => utils::lookup_artwork_url(state, track).await,
+        None => None,
+    };
+    let Some(image_url) = image_url else {
+        clear_cover(state, path, last_cover).await;
+        return;
+    };
+    if last_cover.as_ref() == Some(&image_url) {
+        return;
+    }
+
+    let bytes = match reqwest::get(&image_url).await.and_then(|resp| resp.error_for_status()) {
+        Ok(resp) => resp.bytes().await,
+        Err(e) => Err(e),
+    };
+    match bytes {
+        Ok(bytes) => match utils::write_atomically(path, &bytes).await {
+            Ok(()) => *last_cover = Some(image_url),
+            Err(e) => {
+                warn!("Failed to write cover artwork {}: {}", path.display(), e);
+                state.metrics.record_integration_error("text_output", e);
+            }
+        },
+        Err(e) => {
+            warn!("Failed to download cover artwork: {}", e);
+            state.metrics.record_integration_error("text_output", e);
+            clear_cover(state, path, last_cover).await;
+        }
+    }
+}
+
+async fn clear_cover(state: &AppState, path: &Path, last_cover: &mut Option<String>) {
+    *last_cover = None;
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            warn!("Failed to remove cover artwork {}: {}", path.display(), e);
+            state.metrics.record_integration_error("text_output", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn removes_the_cover_when_no_track_is_shown() {
+        let state = AppState::test();
+        let path = std::env::temp_dir().join(format!("rusty-tapes-cover-{}.jpg", std::process::id()));
+        std::fs::write(&path, b"previous cover").unwrap();
+        let mut last_cover = Some("https://example.com/previous.jpg".to_string());
+
+        write_cover(&state, &path, None, &mut last_cover).await;
+        assert!(!path.exists());
+        assert_eq!(last_cover, None);
+
+        // Clearing a cover that is already gone is not an error.
+        write_cover(&state, &path, None, &mut last_cover).await;
+        assert!(!state.metrics.integrations().contains_key("text_output"));
+    }
+
+    #[tokio::test]
+    async fn withheld_artwork_removes_the_previous_cover() {
+        let state = AppState::test();
+        let rule = toml::from_str("artist = \"Portishead\"\naction = \"redact\"\nredact = [\"artwork\"]").unwrap();
+        let privacy = crate::privacy::PrivacyFilter::new(&[rule]).unwrap();
+        let track = TrackInfo { artwork_url: Some("https://example.com/roads.jpg".to_string()), ..TrackInfo::test("Roads", "Portishead", 305.0, 0.0) };
+        let track = privacy.filter_track(PrivacySink::TextOutput, &track).unwrap();
+
+        let path = std::env::temp_dir().join(format!("rusty-tapes-withheld-cover-{}.jpg", std::process::id()));
+        std::fs::write(&path, b"previous cover").unwrap();
+        let mut last_cover = Some("https://example.com/previous.jpg".to_string());
+
+        write_cover(&state, &path, Some(&track), &mut last_cover).await;
+        assert!(!path.exists());
+        assert_eq!(last_cover, None);
+    }
+}
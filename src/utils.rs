@@ -1,145 +1,16 @@
-use std::{os::macos::raw::stat, sync::Arc, thread::sleep, time::Duration};
+use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 
-use clap::Parser;
 use serde_json::json;
+use tokio::io::AsyncWriteExt;
 use tracing::{info, warn};
 
-use crate::models::{AppState, Args, TrackInfo, TrackInfoC};
+use crate::{config::{DiscordConfig, PrivacySink}, models::{AppState, Args, TrackInfo}, sources, templates};
 use std::sync::atomic::Ordering;
 use yet_another_discord_rpc::DiscordRpc;
 
-#[link(name = "macos-helper")]
-extern "C" {
-    fn is_music_playing() -> bool;
-    fn get_current_track_info() -> TrackInfoC;
-    fn free_track_info(info: *mut TrackInfoC);
-}
-
-pub fn listen_for_track(state: Arc<AppState>) {
-    let state_clone = state.clone();
-    info!("Starting track listener thread");
-    
-    tokio::task::spawn_blocking(move || {
-        unsafe {
-            let mut track_info = TrackInfoC {
-                track_name: std::ptr::null(),
-                artist_name: std::ptr::null(),
-                progress: 0.0,
-                duration: 0.0,
-                genre: std::ptr::null(),
-                favourited: false,
-                played_count: 0,
-                album: std::ptr::null(),
-            };
-
-            let mut last_track_identifier: Option<String> = None;
-            let mut was_playing = false;
-
-            loop {
-                let is_currently_playing = is_music_playing();
-                let play_state_changed = was_playing != is_currently_playing;
-                
-                if is_currently_playing {
-                    free_track_info(&mut track_info);
-                    track_info = get_current_track_info();
-                    
-                    if !track_info.track_name.is_null() {
-                        let track_name = std::ffi::CStr::from_ptr(track_info.track_name).to_string_lossy();
-                        let artist_name = if !track_info.artist_name.is_null() {
-                            std::ffi::CStr::from_ptr(track_info.artist_name).to_string_lossy()
-                        } else {
-                            "Unknown".into()
-                        };
-                        let genre = if !track_info.genre.is_null() {
-                            std::ffi::CStr::from_ptr(track_info.genre).to_string_lossy()
-                        } else {
-                            "Unknown".into()
-                        };
-                        let album = if !track_info.album.is_null() {
-                            std::ffi::CStr::from_ptr(track_info.album).to_string_lossy()
-                        } else {
-                            "Unknown".into()
-                        };
-
-                        let track_identifier = format!("{}|{}", track_name, artist_name);
-                        let track_changed = last_track_identifier.as_ref() != Some(&track_identifier);
-
-                        if track_changed || play_state_changed {
-                            let new_track = TrackInfo {
-                                track_name: track_name.to_string(),
-                                artist_name: artist_name.to_string(),
-                                progress: track_info.progress,
-                                duration: track_info.duration,
-                                genre: genre.to_string(),
-                                favourited: track_info.favourited,
-                                played_count: track_info.played_count,
-                                album: album.to_string(),
-                            };
-
-                            {
-                                let mut last_info = state_clone.last_track_info.lock().unwrap();
-                                *last_info = Some(new_track.clone());
-                            }
-
-                            *state_clone.last_update.lock().unwrap() = std::time::Instant::now();
-                            state_clone.is_playing.store(true, Ordering::SeqCst);
-                            
-                            if track_changed {
-                                state_clone.scrobble_sent.store(false, Ordering::SeqCst);
-                                last_track_identifier = Some(track_identifier);
-                            }
-
-                            let _ = state_clone.client_sender.send(new_track);
-                        }
-                    } else {
-                        let track_identifier = "Radio/Mix|Unknown".to_string();
-                        let track_changed = last_track_identifier.as_ref() != Some(&track_identifier);
-
-                        if track_changed || play_state_changed {
-                            info!("Current track name is null. Most likely radio or mix is playing.");
-                            
-                            let net_track = TrackInfo {
-                                track_name: "Radio/Mix".to_string(),
-                                artist_name: "Unknown".to_string(),
-                                progress: 0.0,
-                                duration: 0.0,
-                                genre: "Unknown".to_string(),
-                                favourited: false,
-                                played_count: 0,
-                                album: "Unknown".to_string(),
-                            };
-                            
-                            {
-                                let mut last_info = state_clone.last_track_info.lock().unwrap();
-                                *last_info = Some(net_track.clone());
-                            }
-                            
-                            *state_clone.last_update.lock().unwrap() = std::time::Instant::now();
-                            state_clone.is_playing.store(true, Ordering::SeqCst);
-                            let _ = state_clone.client_sender.send(net_track);
-                            last_track_identifier = Some(track_identifier);
-                        }
-                    }
-                } else if play_state_changed {
-                    state_clone.is_playing.store(false, Ordering::SeqCst);
-                    if let Some(last_info) = &*state_clone.last_track_info.lock().unwrap() {
-                        let pause_track = TrackInfo {
-                            progress: 0.0,
-                            duration: -1.0,
-                            ..last_info.clone()
-                        };
-                        let _ = state_clone.client_sender.send(pause_track);
-                    }
-                }
-
-                was_playing = is_currently_playing;
-                sleep(Duration::from_secs(1));
-            }
-        }
-    });
-}
+const ARTWORK_CACHE_CAPACITY: usize = 500;
 
-pub fn discord_rpc_task(state: Arc<AppState>, client_id: &str) {
+pub fn discord_rpc_task(state: Arc<AppState>, client_id: &str, config: DiscordConfig) {
     info!("Starting Discord RPC task");
 
     let state_clone = state.clone();
@@ -147,61 +18,56 @@ pub fn discord_rpc_task(state: Arc<AppState>, client_id: &str) {
     tokio::spawn(async move {
         let mut rpc = DiscordRpc::new(&client_id).await.expect("Failed to create Discord RPC client");
         rpc.start_activity(None).await.expect("Failed to start activity");
+        state_clone.metrics.set_integration_connected("discord", true);
 
+        let idle_timeout = config.idle_timeout_secs.map(std::time::Duration::from_secs);
+        let mut idle_deadline: Option<tokio::time::Instant> = None;
         let mut reciever = state_clone.client_sender.subscribe();
-        while let Ok(track) = reciever.recv().await {
-
-            let resp = reqwest::get(
-                format!("https://itunes.apple.com/search?term={}&country=us&limit=25&media=music&entity=musicTrack&attribute=songTerm",
-                    urlencoding::encode(&track.track_name)
-                )
-            ).await;
-
-            let image_url: Option<String> = match resp {
-                Ok(resp) => {
-                    if let Ok(json) = resp.json::<serde_json::Value>().await {
-                        json.get("results")
-                            .and_then(|r| r.as_array())
-                            .and_then(|results| {
-                                results.iter().find_map(|result| {
-                                    let artist = result.get("artistName")?.as_str()?;
-                                    let track_name = result.get("trackName")?.as_str()?;
-                                    if artist.eq_ignore_ascii_case(&track.artist_name) 
-                                        && track_name.eq_ignore_ascii_case(&track.track_name) {
-                                        let artwork = result.get("artworkUrl100")?.as_str()?;
-                                        Some(artwork.replace("100x100bb.jpg", "512x512bb.jpg"))
-                                    } else {
-                                        None
-                                    }
-                                })
-                            })
-                    } else {
-                        None
-                    }
+        loop {
+            let idle = async {
+                match idle_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
                 }
-                Err(_) => None,
             };
+            let received = tokio::select! {
+                received = reciever.recv() => received,
+                _ = idle => {
+                    idle_deadline = None;
+                    clear_activity(&mut rpc, "Cleared idle Discord RPC activity").await;
+                    continue;
+                }
+            };
+            let event = match received {
+                Ok(event) => event,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Discord RPC task lagged behind, dropped {} messages", skipped);
+                    state_clone.metrics.broadcast_lag_drops.fetch_add(skipped, Ordering::Relaxed);
+                    continue;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            };
+
+            let stopped = event.track.is_stopped();
+            idle_deadline = idle_timeout.filter(|_| event.track.is_paused() && !stopped)
+                .map(|timeout| tokio::time::Instant::now() + timeout);
+
+            let shown = state_clone.privacy.filter_event(PrivacySink::Discord, &event)
+                .map(|event| event.track)
+                .filter(|track| !stopped && !config.hides(track));
+            let Some(track) = shown else {
+                clear_activity(&mut rpc, "Hid Discord RPC activity").await;
+                continue;
+            };
+
+            let large_image = match &config.large_image {
+                Some(asset) => asset.clone(),
+                None => lookup_artwork_url(&state_clone, &track).await.unwrap_or_else(|| config.fallback_image.clone()),
+            };
+            let mut activity = build_activity(&config, &track, large_image);
 
-            let mut activity = json!({
-                "type": 2,
-                "details": format!("Listening to {}", track.track_name),
-                "state": format!("by {}", track.artist_name),
-                "assets": {
-                    "large_image": image_url.unwrap_or_else(|| "image_logo".to_string()),
-                    "large_text": format!("Apple Music{}{}", 
-                        if track.genre != "Unknown" { format!(" - {}", track.genre) } else { "".to_string() }, 
-                        if track.played_count > 0 { format!(" (Played {} times)", track.played_count) } else { "".to_string() }),
-                    "small_image": if track.favourited { "favourite" } else { "unfavourite" },
-                    "small_text": if track.favourited { "Favourited" } else { "Not Favourited" },
-                },
-            });
-
-            if track.duration > 0.0 {
-                let start_timestamp = (std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as f64
-                    - track.progress) as i64;
+            if let Some(timing) = track.timing.as_ref().filter(|timing| track.duration > 0.0 && timing.rate > 0.0) {
+                let start_timestamp = (timing.started_at / 1000) as i64;
                 let end_timestamp = start_timestamp + track.duration as i64;
 
                 activity["timestamps"] = json!({
@@ -210,20 +76,173 @@ pub fn discord_rpc_task(state: Arc<AppState>, client_id: &str) {
                 });
             }
 
-            rpc.set_activity(activity).await.expect("Failed to set activity");
-            info!("Updated Discord RPC activity for track: {} by {}", track.track_name, track.artist_name);
+            match rpc.set_activity(activity).await {
+                Ok(_) => {
+                    state_clone.metrics.set_integration_connected("discord", true);
+                    info!("Updated Discord RPC activity for track: {} by {}", track.track_name, track.artist_name);
+                }
+                Err(e) => {
+                    warn!("Failed to set Discord activity: {:?}", e);
+                    state_clone.metrics.set_integration_connected("discord", false);
+                    state_clone.metrics.record_integration_error("discord", format!("{:?}", e));
+                }
+            }
         }
         rpc.stop_activity().await.expect("Failed to stop activity");
         info!("Stopped Discord RPC activity");
     });
 }
 
-pub fn parse_args() -> Args {
-    let mut args = Args::parse();
+/// A null activity clears the presence until the next shown track.
+async fn clear_activity(rpc: &mut DiscordRpc, message: &str) {
+    match rpc.set_activity(serde_json::Value::Null).await {
+        Ok(_) => info!("{}", message),
+        Err(e) => warn!("Failed to clear Discord activity: {:?}", e),
+    }
+}
 
+/// Renders the presence text, assets and buttons for `track`.
+fn build_activity(config: &DiscordConfig, track: &TrackInfo, large_image: String) -> serde_json::Value {
+    let render = |template: &Option<String>, default: String| match template {
+        Some(template) => templates::render(template, track),
+        None => default,
+    };
+
+    let mut assets = json!({
+        "large_image": large_image,
+        "large_text": render(&config.large_text, format!("{}{}{}", sources::display_name(&track.source),
+            if track.genre != "Unknown" { format!(" - {}", track.genre) } else { "".to_string() },
+            if track.played_count > 0 { format!(" (Played {} times)", track.played_count) } else { "".to_string() })),
+    });
+    let small_image = if track.favourited { &config.favourite_image } else { &config.unfavourite_image };
+    if !small_image.is_empty() {
+        assets["small_image"] = json!(small_image);
+        assets["small_text"] = json!(render(&config.small_text, if track.favourited { "Favourited" } else { "Not Favourited" }.to_string()));
+    }
+
+    let mut activity = json!({
+        "type": 2,
+        "details": render(&config.details, format!("Listening to {}", track.track_name)),
+        "state": render(&config.state, format!("by {}", track.artist_name)),
+        "assets": assets,
+    });
+
+    // Discord shows at most two buttons per activity.
+    let buttons: Vec<serde_json::Value> = config.buttons.iter().take(2)
+        .map(|button| json!({
+            "label": templates::render(&button.label, track),
+            "url": templates::render_url(&button.url, track),
+        }))
+        .collect();
+    if !buttons.is_empty() {
+        activity["buttons"] = json!(buttons);
+    }
+    activity
+}
+
+/// Looks up a 512x512 cover artwork URL for `track` on the iTunes Search API,
+/// caching the result so several sinks can share a single lookup.
+pub async fn lookup_artwork_url(state: &AppState, track: &TrackInfo) -> Option<String> {
+    if let Some(artwork_url) = &track.artwork_url {
+        return (!artwork_url.is_empty()).then(|| artwork_url.clone());
+    }
+
+    let cache_key = format!("{}|{}", track.track_name, track.artist_name);
+    if let Some(cached) = state.artwork_cache.lock().unwrap().get(&cache_key) {
+        return cached.clone();
+    }
+
+    let resp = reqwest::get(
+        format!("https://itunes.apple.com/search?term={}&country=us&limit=25&media=music&entity=musicTrack&attribute=songTerm",
+            urlencoding::encode(&track.track_name)
+        )
+    ).await;
+
+    let image_url: Option<String> = match resp {
+        Ok(resp) => {
+            if let Ok(json) = resp.json::<serde_json::Value>().await {
+                json.get("results")
+                    .and_then(|r| r.as_array())
+                    .and_then(|results| {
+                        results.iter().find_map(|result| {
+                            let artist = result.get("artistName")?.as_str()?;
+                            let track_name = result.get("trackName")?.as_str()?;
+                            if artist.eq_ignore_ascii_case(&track.artist_name) 
+                                && track_name.eq_ignore_ascii_case(&track.track_name) {
+                                let artwork = result.get("artworkUrl100")?.as_str()?;
+                                Some(artwork.replace("100x100bb.jpg", "512x512bb.jpg"))
+                            } else {
+                                None
+                            }
+                        })
+                    })
+            } else {
+                None
+            }
+        }
+        Err(e) => {
+            warn!("Artwork lookup failed: {}", e);
+            state.metrics.record_integration_error("itunes", e);
+            state.metrics.artwork_errors.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+    };
+
+    if image_url.is_some() {
+        state.metrics.artwork_hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+        state.metrics.artwork_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let mut cache = state.artwork_cache.lock().unwrap();
+    if cache.len() >= ARTWORK_CACHE_CAPACITY {
+        cache.clear();
+    }
+    cache.insert(cache_key, image_url.clone());
+    image_url
+}
+
+pub fn normalize_args(mut args: Args) -> Args {
     if args.host.to_lowercase() == "localhost" {
         warn!("host is localhost; using 127.0.0.1");
         args.host = "127.0.0.1".to_string();
     }
     args
-}
\ No newline at end of file
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+}
+
+pub fn rfc3339(time: SystemTime) -> String {
+    time::OffsetDateTime::from(time).format(&time::format_description::well_known::Rfc3339).unwrap_or_default()
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so
+/// readers such as OBS never observe a partially written file.
+pub async fn write_atomically(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomically_with(path, contents, tokio::fs::OpenOptions::new()).await
+}
+
+/// Like [`write_atomically`], but the file is only readable by its owner, for
+/// secrets such as OAuth tokens.
+pub async fn write_private_atomically(path: &std::path::Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    #[cfg(unix)]
+    options.mode(0o600);
+    write_atomically_with(path, contents, options).await
+}
+
+async fn write_atomically_with(path: &std::path::Path, contents: &[u8], mut options: tokio::fs::OpenOptions) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    // A leftover temporary file would keep its old permissions.
+    let _ = tokio::fs::remove_file(&temp_path).await;
+    let mut file = options.write(true).create_new(true).open(&temp_path).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    drop(file);
+    tokio::fs::rename(&temp_path, path).await
+}
@@ -0,0 +1,276 @@
+use std::{collections::VecDeque, path::PathBuf, sync::{atomic::Ordering, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{io::AsyncWriteExt, sync::{broadcast::error::RecvError, mpsc}, time::Instant};
+use tracing::{info, warn};
+
+use crate::{config::{PrivacySink, WebhookConfig}, models::{AppState, TrackEvent, TrackEventKind}, templates};
+
+const WEBHOOK_QUEUE_CAPACITY: usize = 32;
+
+struct Delivery {
+    kind: TrackEventKind,
+    body: String,
+}
+
+/// Appends failed deliveries as JSON lines to the configured file.
+struct DeadLetterLog {
+    path: Option<PathBuf>,
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl DeadLetterLog {
+    async fn record(&self, webhook: &WebhookConfig, delivery: &Delivery, reason: &str) {
+        warn!("Webhook {} failed permanently: {}", webhook.display_name(), reason);
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let line = serde_json::json!({
+            "timestamp": unix_timestamp(),
+            "webhook": webhook.display_name(),
+            "url": webhook.url,
+            "event": delivery.kind,
+            "reason": reason,
+            "body": delivery.body,
+        });
+
+        let _guard = self.lock.lock().await;
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+            file.write_all(format!("{}\n", line).as_bytes()).await
+        }.await;
+        if let Err(e) = result {
+            warn!("Failed to write webhook dead letter log {}: {}", path.display(), e);
+        }
+    }
+}
+
+pub fn webhook_task(state: Arc<AppState>, webhooks: Vec<WebhookConfig>, dead_letter_file: Option<PathBuf>) {
+    info!("Starting webhook task with {} webhook(s)", webhooks.len());
+
+    let client = reqwest::Client::new();
+    let dead_letters = Arc::new(DeadLetterLog { path: dead_letter_file, lock: tokio::sync::Mutex::new(()) });
+
+    let workers: Vec<(Arc<WebhookConfig>, mpsc::Sender<Delivery>)> = webhooks.into_iter().map(|webhook| {
+        let webhook = Arc::new(webhook);
+        let (sender, receiver) = mpsc::channel(WEBHOOK_QUEUE_CAPACITY);
+        tokio::spawn(webhook_worker(state.clone(), client.clone(), webhook.clone(), receiver, dead_letters.clone()));
+        (webhook, sender)
+    }).collect();
+
+    tokio::spawn(async move {
+        let mut receiver = state.client_sender.subscribe();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Webhook task lagged behind, dropped {} messages", skipped);
+                    state.metrics.broadcast_lag_drops.fetch_add(skipped, Ordering::Relaxed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let Some(event) = state.privacy.filter_event(PrivacySink::Webhooks, &event) else {
+                continue;
+            };
+
+            let kind = event.kind;
+            for (webhook, sender) in &workers {
+                if !webhook.wants(kind) {
+                    continue;
+                }
+                let delivery = Delivery { kind, body: render_body(webhook, kind, &event) };
+                if let Err(mpsc::error::TrySendError::Full(delivery)) = sender.try_send(delivery) {
+                    dead_letters.record(webhook, &delivery, "delivery queue is full").await;
+                }
+            }
+        }
+    });
+}
+
+fn render_body(webhook: &WebhookConfig, kind: TrackEventKind, event: &TrackEvent) -> String {
+    match &webhook.body_template {
+        Some(template) if webhook.content_type.contains("json") => templates::render_event_json(template, event),
+        Some(template) => templates::render_event(template, event),
+        None => serde_json::json!({
+            "event": kind,
+            "id": event.id,
+            "seq": event.seq,
+            "timestamp": unix_timestamp(),
+            "track": event.track,
+            "previous_play": event.ended,
+        }).to_string(),
+    }
+}
+
+async fn webhook_worker(state: Arc<AppState>, client: reqwest::Client, webhook: Arc<WebhookConfig>, mut receiver: mpsc::Receiver<Delivery>, dead_letters: Arc<DeadLetterLog>) {
+    let mut recent_deliveries: VecDeque<Instant> = VecDeque::new();
+
+    while let Some(delivery) = receiver.recv().await {
+        if let Some(max_per_minute) = webhook.max_per_minute {
+            wait_for_rate_limit(&mut recent_deliveries, max_per_minute).await;
+        }
+
+        let mut attempt = 0;
+        loop {
+            let error = match send(&client, &webhook, &delivery).await {
+                Ok(()) => {
+                    state.metrics.set_integration_connected("webhooks", true);
+                    break;
+                }
+                Err(error) => error,
+            };
+
+            warn!("Webhook {} delivery attempt {} failed: {}", webhook.display_name(), attempt + 1, error.message);
+            state.metrics.record_integration_error("webhooks", &error.message);
+
+            if !error.retryable || attempt >= webhook.max_retries {
+                state.metrics.set_integration_connected("webhooks", false);
+                dead_letters.record(&webhook, &delivery, &error.message).await;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(webhook.retry_backoff_ms.saturating_mul(1 << attempt.min(16)))).await;
+            attempt += 1;
+        }
+    }
+}
+
+async fn wait_for_rate_limit(recent_deliveries: &mut VecDeque<Instant>, max_per_minute: u32) {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    while recent_deliveries.front().is_some_and(|sent| sent.elapsed() >= WINDOW) {
+        recent_deliveries.pop_front();
+    }
+    if recent_deliveries.len() >= max_per_minute.max(1) as usize {
+        if let Some(oldest) = recent_deliveries.pop_front() {
+            tokio::time::sleep(WINDOW.saturating_sub(oldest.elapsed())).await;
+        }
+    }
+    recent_deliveries.push_back(Instant::now());
+}
+
+struct DeliveryError {
+    message: String,
+    retryable: bool,
+}
+
+async fn send(client: &reqwest::Client, webhook: &WebhookConfig, delivery: &Delivery) -> Result<(), DeliveryError> {
+    let mut request = client.post(&webhook.url)
+        .timeout(Duration::from_millis(webhook.timeout_ms))
+        .header("Content-Type", &webhook.content_type)
+        .header("X-Rusty-Tapes-Event", delivery.kind.as_str());
+
+    for (name, value) in &webhook.headers {
+        request = request.header(name, value);
+    }
+    if let Some(secret) = &webhook.secret {
+        request = request.header("X-Rusty-Tapes-Signature", format!("sha256={}", sign(secret, &delivery.body)));
+    }
+
+    let response = request.body(delivery.body.clone()).send().await.map_err(|e| DeliveryError {
+        message: e.to_string(),
+        retryable: true,
+    })?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(DeliveryError {
+            message: format!("receiver responded with {}", status),
+            retryable: status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS,
+        })
+    }
+}
+
+/// Hex-encoded HMAC-SHA256 of `body` keyed with the webhook secret.
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{http::{HeaderMap, StatusCode}, routing::post, Router};
+
+    fn webhook(url: &str, extra: &str) -> WebhookConfig {
+        toml::from_str(&format!("url = \"{}\"\n{}", url, extra)).expect("valid webhook config")
+    }
+
+    /// Serves a receiver on a local port that answers with `status` and
+    /// forwards each request's signature header and body.
+    async fn receiver(status: StatusCode) -> (String, mpsc::UnboundedReceiver<(Option<String>, String)>) {
+        let (requests, received) = mpsc::unbounded_channel();
+        let app = Router::new().route("/hook", post(move |headers: HeaderMap, body: String| async move {
+            let signature = headers.get("X-Rusty-Tapes-Signature").and_then(|value| value.to_str().ok()).map(str::to_string);
+            let _ = requests.send((signature, body));
+            status
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, received)
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8",
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_bodies() {
+        let (url, mut received) = receiver(StatusCode::OK).await;
+        let webhook = webhook(&url, "secret = \"s3cret\"");
+        let delivery = Delivery { kind: TrackEventKind::TrackChanged, body: r#"{"event":"track_changed"}"#.to_string() };
+
+        assert!(send(&reqwest::Client::new(), &webhook, &delivery).await.is_ok());
+        let (signature, body) = received.recv().await.unwrap();
+        assert_eq!(body, delivery.body);
+        assert_eq!(signature, Some(format!("sha256={}", sign("s3cret", &delivery.body))));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_but_not_client_errors() {
+        let client = reqwest::Client::new();
+        let delivery = Delivery { kind: TrackEventKind::Paused, body: "{}".to_string() };
+
+        let (url, _received) = receiver(StatusCode::SERVICE_UNAVAILABLE).await;
+        let error = send(&client, &webhook(&url, ""), &delivery).await.unwrap_err();
+        assert!(error.retryable);
+
+        let (url, _received) = receiver(StatusCode::BAD_REQUEST).await;
+        let error = send(&client, &webhook(&url, ""), &delivery).await.unwrap_err();
+        assert!(!error.retryable);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_waits_for_a_free_slot() {
+        let mut recent = VecDeque::new();
+        let started = Instant::now();
+
+        wait_for_rate_limit(&mut recent, 2).await;
+        tokio::time::advance(Duration::from_secs(10)).await;
+        wait_for_rate_limit(&mut recent, 2).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        // The window slides from the oldest delivery, not the latest.
+        wait_for_rate_limit(&mut recent, 2).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        assert_eq!(recent.len(), 2);
+
+        wait_for_rate_limit(&mut recent, 2).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(70));
+    }
+}